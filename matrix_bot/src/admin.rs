@@ -0,0 +1,281 @@
+use std::{sync::OnceLock, time::Instant};
+
+use anyhow::{anyhow, Result};
+use matrix_bot_core::{
+    command::{self, Access, Command, Context},
+    logger,
+    matrix::{client::Client, e2ee::confirm, room::Room},
+    plugin::{supervisor, PluginsHandle},
+};
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// 注册管理命令，仅管理员可用；设置了管理房间时只能在该房间使用
+///
+/// 管理房间无法解析时仍然注册命令，可以在任意房间使用，避免 bot 无法管理
+pub async fn register(
+    client: &Client,
+    admin_room: Option<&str>,
+    plugins: Option<PluginsHandle>,
+) -> Result<()> {
+    STARTED.get_or_init(Instant::now);
+
+    let room_id = match admin_room {
+        Some(room) => match Room::new(client, room).await {
+            Ok(room) => {
+                let room_id = room.0.room_id().to_owned();
+                // 验证请求的表情发送到管理房间确认
+                confirm::set_room(room);
+                Some(room_id)
+            }
+            Err(e) => {
+                log::error!(
+                    "resolve admin room {} failed, admin commands are available in all rooms: {}",
+                    room,
+                    e
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
+    let restart_handle = plugins.clone();
+    let commands = [
+        Command::new("plugins", list_plugins).help("查看插件运行状态"),
+        Command::new("plugin", move |ctx| plugin(ctx, restart_handle.clone()))
+            .usage("restart <name>")
+            .help("使用当前配置重启插件"),
+        Command::new("reload", move |ctx| reload(ctx, plugins.clone())).help("重新读取配置文件"),
+        Command::new("rooms", rooms).help("查看已加入的房间"),
+        Command::new("devices", devices).help("查看 bot 的设备"),
+        Command::new("verify", verify)
+            .usage("[confirm|cancel <code>]")
+            .help("查看或确认等待中的设备验证，回复验证消息时可省略验证码"),
+        Command::new("uptime", uptime).help("查看运行时间"),
+        Command::new("loglevel", loglevel)
+            .usage("[<module> <level|reset>]")
+            .help("查看或修改模块的日志级别"),
+    ];
+
+    for cmd in commands {
+        let cmd = cmd.access(Access::admin_only());
+        let cmd = match &room_id {
+            Some(room_id) => cmd.rooms([room_id.clone()]),
+            None => cmd,
+        };
+        command::register(cmd);
+    }
+
+    match &room_id {
+        Some(room_id) => log::info!("admin commands registered in {}", room_id),
+        None => log::info!("admin commands registered"),
+    }
+    Ok(())
+}
+
+async fn list_plugins(ctx: Context) -> Result<()> {
+    let status = supervisor::status();
+    if status.is_empty() {
+        ctx.reply("没有运行中的插件", false).await?;
+        return Ok(());
+    }
+
+    let lines = status
+        .iter()
+        .map(|(name, status)| {
+            let since = status.since.elapsed().unwrap_or_default().as_secs();
+            format!(
+                "{}: {}，持续 {}，重启 {} 次",
+                name,
+                status.state,
+                format_secs(since),
+                status.restarts
+            )
+        })
+        .collect::<Vec<_>>();
+    ctx.reply(&lines.join("\n"), false).await?;
+    Ok(())
+}
+
+async fn plugin(ctx: Context, plugins: Option<PluginsHandle>) -> Result<()> {
+    let plugins = plugins.ok_or(anyhow!("插件未加载"))?;
+    match (ctx.args.get(0), ctx.args.get(1)) {
+        (Some("restart"), Some(name)) => {
+            plugins.restart(name).await?;
+            ctx.reply(&format!("已重启 {}", name), false).await?;
+        }
+        _ => {
+            let msg = format!("用法：{}plugin restart <name>", command::prefix());
+            ctx.reply(&msg, false).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn reload(ctx: Context, plugins: Option<PluginsHandle>) -> Result<()> {
+    let plugins = plugins.ok_or(anyhow!("插件未加载"))?;
+    plugins.reload().await?;
+    ctx.reply("配置已重新加载，详情见日志", false).await?;
+    Ok(())
+}
+
+async fn rooms(ctx: Context) -> Result<()> {
+    let client = ctx.room.0.client();
+    let mut lines = Vec::new();
+    for room in client.joined_rooms() {
+        let name = room
+            .display_name()
+            .await
+            .map(|n| n.to_string())
+            .unwrap_or_default();
+        lines.push(format!(
+            "{} ({})，{} 名成员{}",
+            name,
+            room.room_id(),
+            room.joined_members_count(),
+            if room.is_encrypted().await.unwrap_or(false) {
+                "，已加密"
+            } else {
+                ""
+            }
+        ));
+    }
+    for room in client.invited_rooms() {
+        lines.push(format!("{}（待接受邀请）", room.room_id()));
+    }
+    if lines.is_empty() {
+        lines.push("没有加入任何房间".to_string());
+    }
+    ctx.reply(&lines.join("\n"), false).await?;
+    Ok(())
+}
+
+async fn devices(ctx: Context) -> Result<()> {
+    let client = ctx.room.0.client();
+    let user_id = client.user_id().ok_or(anyhow!("未登录"))?.to_owned();
+    let current = client.device_id().map(|d| d.to_owned());
+    let response = client.devices().await?;
+
+    let mut lines = Vec::new();
+    for device in response.devices {
+        let verified = client
+            .encryption()
+            .get_device(&user_id, &device.device_id)
+            .await
+            .ok()
+            .flatten()
+            .map_or(false, |d| d.is_verified());
+        lines.push(format!(
+            "{} {}{}{}",
+            device.device_id,
+            device.display_name.as_deref().unwrap_or("-"),
+            if verified {
+                "，已验证"
+            } else {
+                "，未验证"
+            },
+            if current.as_ref() == Some(&device.device_id) {
+                "（当前设备）"
+            } else {
+                ""
+            }
+        ));
+    }
+    ctx.reply(&lines.join("\n"), false).await?;
+    Ok(())
+}
+
+async fn verify(ctx: Context) -> Result<()> {
+    let accept = match ctx.args.get(0) {
+        Some("confirm") => true,
+        Some("cancel") => false,
+        Some(_) => {
+            let msg = format!("用法：{}verify [confirm|cancel <code>]", command::prefix());
+            ctx.reply(&msg, false).await?;
+            return Ok(());
+        }
+        None => {
+            let list = confirm::list();
+            let msg = if list.is_empty() {
+                "没有等待确认的验证".to_string()
+            } else {
+                list.iter()
+                    .map(|(code, device, emojis)| format!("{} {}\n{}", code, device, emojis))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            };
+            ctx.reply(&msg, false).await?;
+            return Ok(());
+        }
+    };
+
+    let found = match (ctx.args.get(1), ctx.in_reply_to()) {
+        (Some(code), _) => confirm::confirm(code, accept),
+        (None, Some(event_id)) => confirm::confirm_event(event_id, accept),
+        (None, None) => return Err(anyhow!("缺少验证码")),
+    };
+    let msg = match (found, accept) {
+        (false, _) => "没有找到对应的验证",
+        (true, true) => "已确认",
+        (true, false) => "已取消",
+    };
+    ctx.reply(msg, false).await?;
+    Ok(())
+}
+
+async fn uptime(ctx: Context) -> Result<()> {
+    let secs = STARTED.get_or_init(Instant::now).elapsed().as_secs();
+    ctx.reply(&format!("已运行 {}", format_secs(secs)), false)
+        .await?;
+    Ok(())
+}
+
+async fn loglevel(ctx: Context) -> Result<()> {
+    let msg = match (ctx.args.get(0), ctx.args.get(1)) {
+        (None, _) => {
+            let levels = logger::levels();
+            if levels.is_empty() {
+                "没有修改过日志级别".to_string()
+            } else {
+                levels
+                    .iter()
+                    .map(|(module, level)| format!("{}: {}", module, level))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+        }
+        (Some(module), Some("reset")) => {
+            logger::set_level(module, None)?;
+            format!("{} 已恢复默认日志级别", module)
+        }
+        (Some(module), Some(level)) => {
+            let level = level
+                .parse::<log::LevelFilter>()
+                .map_err(|_| anyhow!("无效的日志级别：{}", level))?;
+            logger::set_level(module, Some(level))?;
+            format!("{} 日志级别已设置为 {}", module, level)
+        }
+        (Some(_), None) => format!(
+            "用法：{}loglevel <module> <off|error|warn|info|debug|trace|reset>",
+            command::prefix()
+        ),
+    };
+    ctx.reply(&msg, false).await?;
+    Ok(())
+}
+
+fn format_secs(secs: u64) -> String {
+    let (days, hours, minutes, secs) = (
+        secs / 86400,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+    );
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", secs),
+        (0, 0, _) => format!("{}m{}s", minutes, secs),
+        (0, _, _) => format!("{}h{}m", hours, minutes),
+        _ => format!("{}d{}h", days, hours),
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use matrix_bot_core::{
+    matrix::{
+        client::{Auth, Client},
+        e2ee::{self, confirm},
+    },
+    matrix_sdk::{
+        config::SyncSettings,
+        ruma::{api::client::uiaa, DeviceId, OwnedDeviceId, UserId},
+    },
+    plugin::CancellationToken,
+};
+
+use crate::DevicesCommand;
+
+/// 旧版本与重置数据库后登录的设备名
+const DEVICE_NAME: &str = "matrix_bot";
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+pub async fn run(
+    client: &Client,
+    auth: Auth<'_>,
+    data: &Path,
+    command: &DevicesCommand,
+) -> Result<()> {
+    match command {
+        DevicesCommand::List => list(client).await,
+        DevicesCommand::Delete { device_ids, stale } => {
+            let mut device_ids = device_ids
+                .iter()
+                .map(|id| OwnedDeviceId::from(id.as_str()))
+                .collect::<Vec<_>>();
+            if *stale {
+                device_ids.extend(stale_devices(client).await?);
+            }
+            delete(client, auth, device_ids).await
+        }
+        DevicesCommand::Rename { device_id, name } => {
+            client
+                .rename_device(<&DeviceId>::from(device_id.as_str()), name)
+                .await?;
+            println!("renamed {} to {}", device_id, name);
+            Ok(())
+        }
+        DevicesCommand::Verify { user_id, device_id } => {
+            verify(client, data, user_id, device_id).await
+        }
+    }
+}
+
+async fn list(client: &Client) -> Result<()> {
+    let user_id = client.user_id().ok_or(anyhow!("not logged in"))?;
+    e2ee::print_devices(user_id, client).await;
+    if let Some(device_id) = client.device_id() {
+        println!("current device: {}", device_id);
+    }
+    Ok(())
+}
+
+/// 除当前设备外，名称为 `matrix_bot` 的设备
+async fn stale_devices(client: &Client) -> Result<Vec<OwnedDeviceId>> {
+    let current = client.device_id().map(|d| d.to_owned());
+    let response = client.devices().await?;
+    Ok(response
+        .devices
+        .into_iter()
+        .filter(|d| d.display_name.as_deref() == Some(DEVICE_NAME))
+        .map(|d| d.device_id)
+        .filter(|id| current.as_ref() != Some(id))
+        .collect())
+}
+
+async fn delete(client: &Client, auth: Auth<'_>, device_ids: Vec<OwnedDeviceId>) -> Result<()> {
+    if device_ids.is_empty() {
+        println!("no device to delete");
+        return Ok(());
+    }
+    if let Some(current) = client.device_id() {
+        if device_ids.iter().any(|id| id == current) {
+            return Err(anyhow!(
+                "{} is the current device of the bot, it can't be deleted",
+                current
+            ));
+        }
+    }
+
+    // 删除设备需要服务器的二次认证
+    if let Err(e) = client.delete_devices(&device_ids, None).await {
+        let response = match e.uiaa_response() {
+            Some(response) => response,
+            None => return Err(e.into()),
+        };
+        let user_id = client.user_id().ok_or(anyhow!("not logged in"))?;
+        let password = auth.password().await?;
+        let mut auth_data = uiaa::Password::new(
+            uiaa::UserIdentifier::UserIdOrLocalpart(user_id.as_str()),
+            &password,
+        );
+        auth_data.session = response.session.as_deref();
+        client
+            .delete_devices(&device_ids, Some(uiaa::AuthData::Password(auth_data)))
+            .await?;
+    }
+
+    for device_id in device_ids {
+        println!("deleted {}", device_id);
+    }
+    Ok(())
+}
+
+/// 向设备发起表情验证，在终端确认
+async fn verify(client: &Client, data: &Path, user_id: &str, device_id: &str) -> Result<()> {
+    let user_id = UserId::parse(user_id)?;
+    let shutdown = CancellationToken::new();
+    let (_handlers, server) = e2ee::sync(client, data, shutdown.clone())?;
+    let sync = {
+        let client = client.0.clone();
+        tokio::spawn(async move { client.sync(SyncSettings::default()).await })
+    };
+
+    let result: Result<()> = async {
+        let device = client
+            .encryption()
+            .get_device(&user_id, device_id.into())
+            .await?
+            .ok_or(anyhow!("device {} of {} not found", device_id, user_id))?;
+        let request = device.request_verification().await?;
+        println!(
+            "verification request sent, accept it on {} {}",
+            user_id, device_id
+        );
+
+        let started = Instant::now();
+        let mut prompted = Vec::new();
+        while !request.is_done() && !request.is_cancelled() && started.elapsed() < VERIFY_TIMEOUT {
+            for (code, device, _) in confirm::list() {
+                if prompted.contains(&code) {
+                    continue;
+                }
+                prompted.push(code.clone());
+                let prompt = format!("Do the emojis of {} match? [y/N] ", device);
+                let accept = tokio::task::spawn_blocking(move || ask(&prompt)).await??;
+                confirm::confirm(&code, accept);
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+
+        if request.is_done() {
+            println!("verified {} {}", user_id, device_id);
+            Ok(())
+        } else {
+            Err(anyhow!("verification cancelled or timed out"))
+        }
+    }
+    .await;
+
+    shutdown.cancel();
+    let _ = server.await;
+    sync.abort();
+    result
+}
+
+fn ask(prompt: &str) -> Result<bool> {
+    use std::io::Write;
+
+    print!("{}", prompt);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
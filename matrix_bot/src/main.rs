@@ -1,65 +1,266 @@
-use std::path::{Path, PathBuf};
+use std::{path::PathBuf, sync::Arc};
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
 use matrix_bot_core::{
-    matrix::{self, client::Client},
-    matrix_sdk::config::SyncSettings,
+    command,
+    config::{Config, CoreConfig},
+    health, logger,
+    matrix::{self, client::Auth},
+    matrix_sdk::{config::SyncSettings, ruma::UserId, LoopCtrl},
+    metrics,
+    plugin::{CancellationToken, Registry},
 };
 
+mod admin;
+mod devices;
+mod plugins;
+mod verify;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Home Server URL
+    /// Home Server URL, overrides `core.homeserver_url`
     #[arg(short = 's', long, env = "HOMESERVER_URL")]
-    homeserver_url: String,
+    homeserver_url: Option<String>,
 
-    /// Matrix username
+    /// Matrix username, overrides `core.username`
     #[arg(short, long, env = "USERNAME")]
-    username: String,
+    username: Option<String>,
 
-    /// Matrix password
+    /// Matrix password, overrides `core.password`
     #[arg(short, long, env = "PASSWORD")]
-    password: String,
+    password: Option<String>,
+
+    /// Read the Matrix password from a file (e.g. Docker secrets), overrides `core.password_file`
+    #[arg(long, env = "PASSWORD_FILE")]
+    password_file: Option<PathBuf>,
+
+    /// Pre-issued access token, requires `--device-id` and a full user id as username
+    #[arg(long, env = "ACCESS_TOKEN")]
+    access_token: Option<String>,
+
+    /// Device ID of the access token
+    #[arg(long, env = "DEVICE_ID")]
+    device_id: Option<String>,
+
+    /// Passphrase to encrypt the store and session file, overrides `core.store_passphrase`
+    #[arg(long, env = "STORE_PASSPHRASE")]
+    store_passphrase: Option<String>,
+
+    /// Read the store passphrase from a file, overrides `core.store_passphrase_file`
+    #[arg(long, env = "STORE_PASSPHRASE_FILE")]
+    store_passphrase_file: Option<PathBuf>,
+
+    /// Recovery key to encrypt the exported room keys, never stored in the data folder
+    #[arg(long, env = "RECOVERY_KEY")]
+    recovery_key: Option<String>,
+
+    /// Read the recovery key from a file outside the data folder, overrides `core.recovery_key_file`
+    #[arg(long, env = "RECOVERY_KEY_FILE")]
+    recovery_key_file: Option<PathBuf>,
 
     /// Data folder
     #[arg(short, long, env = "DATA_PATH", default_value = "data")]
     data: PathBuf,
 
-    /// Plugin selection
+    /// Config file
+    /// Default: <DATA_PATH>/config.toml
+    #[arg(short, long, env = "CONFIG_PATH")]
+    config: Option<PathBuf>,
+
+    /// Plugin selection, overrides `core.plugins`
     /// Available plugins: yande_popular, webhook, qbittorrent
     /// Example: -P yande_popular,webhook
     /// Example: -P all
-    #[arg(short = 'P', long, env = "PLUGINS", default_value = "all")]
-    plugins: String,
+    #[arg(short = 'P', long, env = "PLUGINS", value_delimiter = ',')]
+    plugins: Vec<String>,
+
+    /// Bot administrators, allowed to run every command, overrides `core.admins`
+    /// Example: -a @alice:example.org,@bob:example.org
+    #[arg(short, long, env = "ADMINS", value_delimiter = ',')]
+    admins: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Log in interactively and save the session, the password is not stored
+    Login,
+    /// Manage the devices of the bot account, the bot must be stopped
+    Devices {
+        #[command(subcommand)]
+        command: DevicesCommand,
+    },
+    /// Answer SAS verifications waiting in the running bot
+    Verify {
+        #[command(subcommand)]
+        command: Option<VerifyCommand>,
+    },
+    /// Config file tools
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Validate the config file without logging in
+    Check,
+}
+
+#[derive(Subcommand, Debug)]
+enum DevicesCommand {
+    /// List the devices and their verification state
+    List,
+    /// Delete devices, asks for the password when it is not set
+    Delete {
+        device_ids: Vec<String>,
+        /// Also delete every other device named "matrix_bot"
+        #[arg(long)]
+        stale: bool,
+    },
+    /// Rename a device
+    Rename { device_id: String, name: String },
+    /// Start an emoji verification with a device and confirm it here
+    Verify { user_id: String, device_id: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum VerifyCommand {
+    /// List verifications waiting for confirmation
+    List,
+    /// Confirm that the emojis match
+    Confirm { code: String },
+    /// Cancel the verification
+    Cancel { code: String },
 }
 
 #[tokio::main]
-async fn main() {
-    env_logger::builder()
-        .filter(None, log::LevelFilter::Info)
-        .filter(Some("matrix_sdk"), log::LevelFilter::Warn)
-        .filter(Some("tracing"), log::LevelFilter::Warn)
-        .filter(Some("ruma_common"), log::LevelFilter::Warn)
-        .init();
+async fn main() -> Result<()> {
+    logger::init(&[
+        (None, log::LevelFilter::Info),
+        (Some("matrix_sdk"), log::LevelFilter::Warn),
+        (Some("tracing"), log::LevelFilter::Warn),
+        (Some("ruma_common"), log::LevelFilter::Warn),
+    ])?;
 
     let args = Args::parse();
+    if let Some(Command::Verify { command }) = &args.command {
+        return verify::run(&args.data, command.as_ref().unwrap_or(&VerifyCommand::List));
+    }
+
+    let registry = plugins::registry();
+    let plugin_folder = args.data.join("plugins");
+
+    let check = matches!(
+        &args.command,
+        Some(Command::Config {
+            command: ConfigCommand::Check,
+        })
+    );
+    // 检查配置时不生成默认配置文件
+    let config = load_config(&args, &registry, !check)?;
+    if check {
+        return check_config(&args, &config, &registry);
+    }
 
+    command::set_prefix(&config.core.command_prefix)?;
+    command::set_admins(config.core.admins.clone());
+    command::set_ignored_users(config.core.ignored_users.clone());
+    command::set_reply_unknown(config.core.reply_unknown_commands);
+    matrix::e2ee::confirm::set_allowlist(config.core.verify_allowlist.clone());
+
+    let homeserver_url = required(&config.core.homeserver_url, "homeserver_url")?;
+    let password = read_password(&config.core)?;
+    let auth = auth(&config.core, password.as_deref())?;
+    let passphrase = read_secret(
+        &config.core.store_passphrase,
+        &config.core.store_passphrase_file,
+    )?;
+    matrix::e2ee::set_recovery_key(&args.data, read_recovery_key(&args, &config.core)?);
+
+    let session_file = args.data.join("session.json");
     let matrix_client = matrix::client::Client::login(
-        &args.homeserver_url,
-        &args.username,
-        &args.password,
-        &args.data.join("session.json"),
+        homeserver_url,
+        auth,
+        passphrase.as_deref(),
+        &session_file,
         &args.data.join("db"),
     )
-    .await
-    .unwrap();
+    .await?;
+
+    match &args.command {
+        Some(Command::Login) => {
+            log::info!("login ok, session: {}", session_file.to_string_lossy());
+            return Ok(());
+        }
+        Some(Command::Devices { command }) => {
+            return devices::run(&matrix_client, auth, &args.data, command).await;
+        }
+        _ => {}
+    }
+
+    // 停止信号，传递给插件与本地验证服务
+    let shutdown = CancellationToken::new();
+
     let mut event_handlers = Vec::new();
-    let e2ee_sync = matrix::e2ee::sync(&matrix_client).unwrap();
+    let (e2ee_handlers, verify_server) =
+        matrix::e2ee::sync(&matrix_client, &args.data, shutdown.clone())?;
+    let keys_export =
+        matrix::e2ee::export_keys_periodically(&matrix_client, &args.data, shutdown.clone());
+
+    event_handlers.extend(e2ee_handlers);
+    event_handlers.push(command::attach(&matrix_client));
+    event_handlers.extend(matrix::invite::attach(
+        &matrix_client,
+        config.core.invites.clone(),
+    ));
 
-    event_handlers.extend(e2ee_sync.0);
+    if let Some(addr) = config.core.health_listen {
+        tokio::spawn(async move {
+            if let Err(e) = health::serve(addr).await {
+                log::error!("health check server stopped: {}", e);
+            }
+        });
+    }
 
-    let _ = load_plugins(&matrix_client, args.data.join("plugins"), &args.plugins);
+    let log_sink = config.core.log_room.as_deref().map(|room| {
+        logger::sink::start(
+            &matrix_client,
+            room,
+            config.core.log_room_level,
+            shutdown.clone(),
+        )
+    });
+
+    let admin_room = config.core.admin_room.clone();
+    let (plugins, plugins_handle) = match registry.start(
+        &matrix_client,
+        Arc::new(config),
+        &plugin_folder,
+        shutdown.clone(),
+    ) {
+        Ok(plugins) => {
+            let handle = plugins.handle();
+            (Some(plugins.watch()), Some(handle))
+        }
+        Err(e) => {
+            log::error!("load plugins failed: {}", e);
+            (None, None)
+        }
+    };
+
+    // 管理房间可能需要等待邀请，不阻塞启动
+    let client = matrix_client.clone();
+    tokio::spawn(async move {
+        if let Err(e) = admin::register(&client, admin_room.as_deref(), plugins_handle).await {
+            log::error!("register admin commands failed: {}", e);
+        }
+    });
 
     let ctrlc = tokio::signal::ctrl_c();
 
@@ -69,9 +270,13 @@ async fn main() {
 
     let client = matrix_client.clone();
 
-    let handle = tokio::spawn(async move {
+    let mut handle = tokio::spawn(async move {
+        let settings = SyncSettings::new().timeout(std::time::Duration::from_secs(30));
         client
-            .sync(SyncSettings::new().timeout(std::time::Duration::from_secs(30)))
+            .sync_with_callback(settings, |_| async {
+                metrics::record_sync();
+                LoopCtrl::Continue
+            })
             .await
     });
 
@@ -80,7 +285,7 @@ async fn main() {
         _=ctrlc => {
             log::info!("Ctrl-c received, stopping");
         }
-        _=handle => {
+        _=&mut handle => {
             log::error!("Syncing stopped");
         }
 
@@ -94,76 +299,199 @@ async fn main() {
         _=ctrlc => {
             log::info!("Ctrl-c received, stopping");
         }
-        _=handle => {
+        _=&mut handle => {
             log::error!("Syncing stopped");
         }
     }
 
+    shutdown.cancel();
+    if let Some(plugins) = plugins {
+        let _ = plugins.await;
+    }
+    let _ = verify_server.await;
+    let _ = keys_export.await;
+    if let Some(log_sink) = log_sink {
+        let _ = log_sink.await;
+    }
+    handle.abort();
+
     log::info!("Stopped");
+    Ok(())
 }
 
-pub fn load_plugins(
-    client: &Client,
-    settings_folder: impl AsRef<Path>,
-    selection: &str,
-) -> Result<()> {
-    std::fs::create_dir_all(&settings_folder)?;
-
-    let selection = selection
-        .split(',')
-        .map(|s| s.trim().to_string())
-        .collect::<Vec<_>>();
-
-    #[cfg(feature = "yande_popular")]
-    {
-        if selection.contains(&"yande_popular".to_string())
-            || selection.contains(&"all".to_string())
-        {
-            let client = client.clone();
-            let settings_folder = settings_folder.as_ref().to_path_buf();
-            std::thread::spawn(move || {
-                let rt = tokio::runtime::Builder::new_current_thread()
-                    .enable_all()
-                    .build()
-                    .unwrap();
-
-                rt.block_on(async move { yande_popular::run(client, settings_folder).await })
-                    .unwrap_or_else(|e| {
-                        log::error!("yande_popular stop: {}", e);
-                    });
-            });
+/// 读取配置文件并合并命令行参数，配置文件不存在且 `create_default` 为真时生成默认配置
+fn load_config(args: &Args, registry: &Registry, create_default: bool) -> Result<Config> {
+    let path = args
+        .config
+        .clone()
+        .unwrap_or_else(|| args.data.join("config.toml"));
+
+    if !path.exists() {
+        if !create_default {
+            return Err(anyhow!("config file {} not found", path.to_string_lossy()));
         }
-    };
+        log::info!("create config file: {}", path.to_string_lossy());
+        registry.write_default_config(&path, args.data.join("plugins"))?;
+        log::error!("please edit config file: {}", path.to_string_lossy());
+    }
 
-    #[cfg(feature = "webhook")]
-    {
-        if selection.contains(&"webhook".to_string()) || selection.contains(&"all".to_string()) {
-            let client = client.clone();
-            let settings_folder = settings_folder.as_ref().to_path_buf();
-            tokio::spawn(async move {
-                webhook::run(client, settings_folder)
-                    .await
-                    .unwrap_or_else(|e| {
-                        log::error!("webhook stop: {}", e);
-                    });
-            });
+    let mut config = Config::load(&path)?;
+    if !config.overrides().is_empty() {
+        log::info!(
+            "config overridden by env: {}",
+            config.overrides().join(", ")
+        );
+    }
+
+    if args.homeserver_url.is_some() {
+        config.core.homeserver_url = args.homeserver_url.clone();
+    }
+    if args.username.is_some() {
+        config.core.username = args.username.clone();
+    }
+    if args.password.is_some() {
+        config.core.password = args.password.clone();
+    }
+    if args.password_file.is_some() {
+        config.core.password_file = args.password_file.clone();
+    }
+    if args.access_token.is_some() {
+        config.core.access_token = args.access_token.clone();
+    }
+    if args.device_id.is_some() {
+        config.core.device_id = args.device_id.clone();
+    }
+    if args.store_passphrase.is_some() {
+        config.core.store_passphrase = args.store_passphrase.clone();
+    }
+    if args.store_passphrase_file.is_some() {
+        config.core.store_passphrase_file = args.store_passphrase_file.clone();
+    }
+    if args.recovery_key_file.is_some() {
+        config.core.recovery_key_file = args.recovery_key_file.clone();
+    }
+    if !args.plugins.is_empty() {
+        config.core.plugins = args.plugins.iter().map(|p| p.trim().to_string()).collect();
+    }
+    if !args.admins.is_empty() {
+        config.core.admins = args
+            .admins
+            .iter()
+            .map(|admin| UserId::parse(admin.trim()))
+            .collect::<Result<_, _>>()
+            .map_err(|e| anyhow!("invalid admin: {}", e))?;
+    }
+
+    Ok(config)
+}
+
+fn check_config(args: &Args, config: &Config, registry: &Registry) -> Result<()> {
+    let mut ok = true;
+    for key in ["homeserver_url", "username"] {
+        let value = match key {
+            "homeserver_url" => &config.core.homeserver_url,
+            _ => &config.core.username,
+        };
+        if value.as_deref().map_or(true, str::is_empty) {
+            println!(
+                "[core] {}: not set (can also be passed by argument or env)",
+                key
+            );
         }
-    };
+    }
+    if let Err(e) = read_password(&config.core).and_then(|p| auth(&config.core, p.as_deref())) {
+        ok = false;
+        println!("[core] {}", e);
+    }
+    if let Err(e) = read_secret(
+        &config.core.store_passphrase,
+        &config.core.store_passphrase_file,
+    ) {
+        ok = false;
+        println!("[core] {}", e);
+    }
+    match read_recovery_key(args, &config.core) {
+        Ok(Some(_)) => {}
+        Ok(None) => println!("[core] recovery key: not set, room keys will not be exported"),
+        Err(e) => {
+            ok = false;
+            println!("[core] {}", e);
+        }
+    }
 
-    #[cfg(feature = "qbittorrent")]
-    {
-        if selection.contains(&"qbittorrent".to_string()) || selection.contains(&"all".to_string())
-        {
-            let client = client.clone();
-            let settings_folder = settings_folder.as_ref().to_path_buf();
-            tokio::spawn(async move {
-                qbittorrent::run(client, settings_folder)
-                    .await
-                    .unwrap_or_else(|e| {
-                        log::error!("qbittorrent stop: {}", e);
-                    });
-            });
+    for (name, result) in registry.check(config) {
+        match result {
+            Ok(_) => println!("[plugins.{}] ok", name),
+            Err(e) => {
+                ok = false;
+                println!("[plugins.{}] {}", name, e);
+            }
         }
-    };
-    Ok(())
+    }
+
+    if ok {
+        println!("{} is valid", config.path().to_string_lossy());
+        Ok(())
+    } else {
+        Err(anyhow!("{} is invalid", config.path().to_string_lossy()))
+    }
+}
+
+fn read_password(core: &CoreConfig) -> Result<Option<String>> {
+    read_secret(&core.password, &core.password_file)
+}
+
+/// 优先使用直接设置的值，其次读取文件
+fn read_secret(value: &Option<String>, file: &Option<PathBuf>) -> Result<Option<String>> {
+    if let Some(value) = value.as_deref().filter(|v| !v.is_empty()) {
+        return Ok(Some(value.to_string()));
+    }
+    match file {
+        Some(path) => {
+            let value = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("can't read {}: {}", path.display(), e))?;
+            Ok(Some(value.trim_end_matches(['\r', '\n']).to_string()))
+        }
+        None => Ok(None),
+    }
+}
+
+/// 读取导出房间密钥使用的恢复密钥，密钥文件不能放在数据目录中
+fn read_recovery_key(args: &Args, core: &CoreConfig) -> Result<Option<String>> {
+    if let Some(file) = &core.recovery_key_file {
+        let inside = match (file.canonicalize(), args.data.canonicalize()) {
+            (Ok(file), Ok(data)) => file.starts_with(data),
+            _ => false,
+        };
+        if inside {
+            return Err(anyhow!(
+                "recovery_key_file {} must not be inside the data folder",
+                file.display()
+            ));
+        }
+    }
+    read_secret(&args.recovery_key, &core.recovery_key_file)
+}
+
+/// 设置了 access token 时使用 token 登录，没有密码时在需要登录时交互输入
+fn auth<'a>(core: &'a CoreConfig, password: Option<&'a str>) -> Result<Auth<'a>> {
+    let username = required(&core.username, "username")?;
+    if let Some(access_token) = core.access_token.as_deref().filter(|t| !t.is_empty()) {
+        return Ok(Auth::AccessToken {
+            user_id: username,
+            device_id: required(&core.device_id, "device_id")?,
+            access_token,
+        });
+    }
+    Ok(match password {
+        Some(password) => Auth::Password { username, password },
+        None => Auth::Interactive { username },
+    })
+}
+
+fn required<'a>(value: &'a Option<String>, key: &str) -> Result<&'a str> {
+    value
+        .as_deref()
+        .filter(|v| !v.is_empty())
+        .ok_or(anyhow!("core.{} is not set", key))
 }
@@ -0,0 +1,18 @@
+use matrix_bot_core::plugin::Registry;
+
+/// 所有已编译的插件，新增插件只需在此注册
+pub fn registry() -> Registry {
+    #[allow(unused_mut)]
+    let mut registry = Registry::new();
+
+    #[cfg(feature = "yande_popular")]
+    registry.register(yande_popular::YandePopular::default());
+
+    #[cfg(feature = "webhook")]
+    registry.register(webhook::Webhook::default());
+
+    #[cfg(feature = "qbittorrent")]
+    registry.register(qbittorrent::Qbittorrent);
+
+    registry
+}
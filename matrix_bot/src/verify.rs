@@ -0,0 +1,52 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use matrix_bot_core::matrix::e2ee::PORT_FILE;
+
+use crate::VerifyCommand;
+
+/// 通过运行中 bot 的本地确认服务处理验证
+pub fn run(data: &Path, command: &VerifyCommand) -> Result<()> {
+    match command {
+        VerifyCommand::List => {
+            let list = request(data, "/verify")?;
+            if list.is_empty() {
+                println!("no verification is waiting");
+            } else {
+                print!("{}", list);
+            }
+        }
+        VerifyCommand::Confirm { code } => {
+            request(data, &format!("/verify/{}", code))?;
+            println!("confirmed {}", code);
+        }
+        VerifyCommand::Cancel { code } => {
+            request(data, &format!("/verify/{}/cancel", code))?;
+            println!("cancelled {}", code);
+        }
+    }
+    Ok(())
+}
+
+fn request(data: &Path, path: &str) -> Result<String> {
+    let port = std::fs::read_to_string(data.join(PORT_FILE))
+        .map_err(|e| anyhow!("can't read {}, is the bot running? {}", PORT_FILE, e))?;
+    let port = port.trim().parse::<u16>()?;
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port))
+        .map_err(|e| anyhow!("can't connect to the bot, is it running? {}", e))?;
+    write!(stream, "GET {} HTTP/1.0\r\nHost: 127.0.0.1\r\n\r\n", path)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    match head.split_whitespace().nth(1) {
+        Some("200") => Ok(body.to_string()),
+        Some("404") => Err(anyhow!("verification not found or already finished")),
+        status => Err(anyhow!("unexpected response: {}", status.unwrap_or(head))),
+    }
+}
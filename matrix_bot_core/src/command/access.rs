@@ -0,0 +1,121 @@
+use std::{
+    collections::HashSet,
+    sync::{OnceLock, RwLock},
+};
+
+use anyhow::Result;
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+use serde::{Deserialize, Serialize};
+
+use crate::matrix::room::Room;
+
+static ADMINS: OnceLock<RwLock<HashSet<OwnedUserId>>> = OnceLock::new();
+
+/// 未配置权限时要求的权限等级，对应房间的版主
+pub const DEFAULT_MIN_POWER_LEVEL: i64 = 50;
+
+/// 命令权限，满足任一条件即可使用
+///
+/// 配置中省略时为 [`Access::default`]，即权限等级不低于 [`DEFAULT_MIN_POWER_LEVEL`]；
+/// 显式写出但不设置任何条件（`access = {}`）时所有人可用
+#[derive(Debug, Clone, Deserialize, Serialize, Hash, Eq, PartialEq)]
+pub struct Access {
+    /// 允许使用的用户
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_users: Vec<OwnedUserId>,
+    /// 房间内权限等级不低于该值的用户可用
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_power_level: Option<i64>,
+    /// 仅 bot 管理员可用
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub admin_only: bool,
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access {
+            min_power_level: Some(DEFAULT_MIN_POWER_LEVEL),
+            ..Access::everyone()
+        }
+    }
+}
+
+impl Access {
+    /// 所有人可用
+    pub fn everyone() -> Self {
+        Access {
+            allow_users: Vec::new(),
+            min_power_level: None,
+            admin_only: false,
+        }
+    }
+
+    pub fn admin_only() -> Self {
+        Access {
+            admin_only: true,
+            ..Access::everyone()
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.admin_only && self.allow_users.is_empty() && self.min_power_level.is_none()
+    }
+
+    pub async fn check(&self, room: &Room, user_id: &UserId) -> Result<bool> {
+        if is_admin(user_id) {
+            return Ok(true);
+        }
+        if self.admin_only {
+            return Ok(false);
+        }
+        if self.is_open() || self.allow_users.iter().any(|u| u == user_id) {
+            return Ok(true);
+        }
+        if let Some(min_power_level) = self.min_power_level {
+            if let Some(member) = room.0.get_member(user_id).await? {
+                return Ok(member.power_level() >= min_power_level);
+            }
+        }
+        Ok(false)
+    }
+}
+
+fn admins() -> &'static RwLock<HashSet<OwnedUserId>> {
+    ADMINS.get_or_init(|| RwLock::new(HashSet::new()))
+}
+
+/// 设置 bot 管理员，管理员可以使用所有命令
+pub fn set_admins(users: impl IntoIterator<Item = OwnedUserId>) {
+    *admins().write().unwrap() = users.into_iter().collect();
+}
+
+pub fn is_admin(user_id: &UserId) -> bool {
+    admins().read().unwrap().contains(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Setting {
+        #[serde(default)]
+        access: Access,
+    }
+
+    #[test]
+    fn test_default() {
+        let setting: Setting = toml::from_str("").unwrap();
+        assert_eq!(
+            setting.access.min_power_level,
+            Some(DEFAULT_MIN_POWER_LEVEL)
+        );
+        assert!(!setting.access.is_open());
+
+        let setting: Setting = toml::from_str("access = {}").unwrap();
+        assert!(setting.access.is_open());
+
+        let setting: Setting = toml::from_str("access = { allow_users = [\"@a:b.c\"] }").unwrap();
+        assert_eq!(setting.access.min_power_level, None);
+    }
+}
@@ -0,0 +1,389 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+};
+
+use anyhow::Result;
+use matrix_sdk::{
+    event_handler::EventHandlerHandle,
+    ruma::{
+        events::room::message::{
+            MessageType, OriginalSyncRoomMessageEvent, Relation, TextMessageEventContent,
+        },
+        EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId,
+    },
+};
+
+use crate::matrix::{client::Client, room::Room};
+
+pub use access::{is_admin, set_admins, Access, DEFAULT_MIN_POWER_LEVEL};
+pub use parse::Args;
+
+mod access;
+mod parse;
+
+pub const DEFAULT_PREFIX: &str = "!";
+
+static PREFIX: OnceLock<String> = OnceLock::new();
+static COMMANDS: OnceLock<RwLock<BTreeMap<String, Arc<Command>>>> = OnceLock::new();
+static IGNORED_USERS: OnceLock<RwLock<HashSet<OwnedUserId>>> = OnceLock::new();
+static REPLY_UNKNOWN: AtomicBool = AtomicBool::new(false);
+
+type Handler =
+    Arc<dyn Fn(Context) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> + Send + Sync>;
+
+pub struct Command {
+    name: String,
+    usage: String,
+    help: String,
+    rooms: Option<HashSet<OwnedRoomId>>,
+    access: Access,
+    room_access: HashMap<OwnedRoomId, Access>,
+    // 注册命令的插件，命令执行时计入该插件的指标
+    plugin: Option<&'static str>,
+    handler: Handler,
+}
+
+impl Command {
+    pub fn new<F, Fut>(name: &str, handler: F) -> Self
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        Command {
+            name: name.to_lowercase(),
+            usage: String::new(),
+            help: String::new(),
+            rooms: None,
+            access: Access::default(),
+            room_access: HashMap::new(),
+            plugin: None,
+            handler: Arc::new(move |ctx| Box::pin(handler(ctx))),
+        }
+    }
+
+    /// 参数说明，例如 `<magnet_url>`
+    pub fn usage(mut self, usage: &str) -> Self {
+        self.usage = usage.to_string();
+        self
+    }
+
+    pub fn help(mut self, help: &str) -> Self {
+        self.help = help.to_string();
+        self
+    }
+
+    /// 限定命令只在指定房间可用，不设置则所有房间可用
+    pub fn rooms(mut self, rooms: impl IntoIterator<Item = OwnedRoomId>) -> Self {
+        self.rooms = Some(rooms.into_iter().collect());
+        self
+    }
+
+    /// 命令的默认权限
+    pub fn access(mut self, access: Access) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// 指定房间内的权限，覆盖默认权限
+    pub fn room_access(mut self, room_id: OwnedRoomId, access: Access) -> Self {
+        self.room_access.insert(room_id, access);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn available_in(&self, room_id: &RoomId) -> bool {
+        self.rooms
+            .as_ref()
+            .map_or(true, |rooms| rooms.contains(room_id))
+    }
+
+    fn access_in(&self, room_id: &RoomId) -> &Access {
+        self.room_access.get(room_id).unwrap_or(&self.access)
+    }
+
+    fn help_line(&self) -> String {
+        let mut line = format!("{}{}", prefix(), self.name);
+        if !self.usage.is_empty() {
+            line.push(' ');
+            line.push_str(&self.usage);
+        }
+        if !self.help.is_empty() {
+            line.push_str(" - ");
+            line.push_str(&self.help);
+        }
+        line
+    }
+}
+
+pub struct Context {
+    pub room: Room,
+    pub event: OriginalSyncRoomMessageEvent,
+    pub args: Args,
+}
+
+impl Context {
+    pub async fn reply(&self, msg: &str, is_markdown: bool) -> Result<OwnedEventId> {
+        self.room
+            .send_relates_msg(msg, self.event.event_id.as_str(), is_markdown)
+            .await
+    }
+
+    pub async fn reply_html(&self, msg: &str, html_msg: &str) -> Result<OwnedEventId> {
+        self.room
+            .send_relates_html(msg, html_msg, self.event.event_id.as_str())
+            .await
+    }
+
+    /// 命令消息所回复的事件
+    pub fn in_reply_to(&self) -> Option<&EventId> {
+        match &self.event.content.relates_to {
+            Some(Relation::Reply { in_reply_to }) => Some(&in_reply_to.event_id),
+            _ => None,
+        }
+    }
+}
+
+fn commands() -> &'static RwLock<BTreeMap<String, Arc<Command>>> {
+    COMMANDS.get_or_init(|| RwLock::new(BTreeMap::new()))
+}
+
+pub fn prefix() -> &'static str {
+    PREFIX.get_or_init(|| DEFAULT_PREFIX.to_string())
+}
+
+/// 设置命令前缀，只能在 [`attach`] 之前调用一次
+pub fn set_prefix(prefix: &str) -> Result<()> {
+    PREFIX
+        .set(prefix.to_string())
+        .map_err(|_| anyhow::anyhow!("command prefix already set"))
+}
+
+/// 注册命令，同名命令会被覆盖
+pub fn register(mut command: Command) {
+    command.plugin = crate::plugin::current();
+    let mut commands = commands().write().unwrap();
+    if commands.contains_key(&command.name) {
+        log::debug!("command {} replaced", command.name);
+    }
+    commands.insert(command.name.clone(), Arc::new(command));
+}
+
+pub fn unregister(name: &str) {
+    commands().write().unwrap().remove(name);
+}
+
+fn ignored_users() -> &'static RwLock<HashSet<OwnedUserId>> {
+    IGNORED_USERS.get_or_init(|| RwLock::new(HashSet::new()))
+}
+
+/// 忽略这些用户（例如其他 bot）发送的命令
+pub fn set_ignored_users(users: impl IntoIterator<Item = OwnedUserId>) {
+    *ignored_users().write().unwrap() = users.into_iter().collect();
+}
+
+fn is_ignored(user_id: &UserId) -> bool {
+    ignored_users().read().unwrap().contains(user_id)
+}
+
+/// 是否回复未知命令，默认不回复，避免与其他 bot 互相回复
+pub fn set_reply_unknown(enabled: bool) {
+    REPLY_UNKNOWN.store(enabled, Ordering::Relaxed);
+}
+
+/// 未知命令的回复，未开启或房间没有可用命令时返回 `None`
+fn unknown_reply(room_id: &RoomId, name: &str) -> Option<String> {
+    if !REPLY_UNKNOWN.load(Ordering::Relaxed) || !has_commands(room_id) {
+        return None;
+    }
+    Some(format!(
+        "未知命令：{}，发送 {}help 查看可用命令",
+        name,
+        prefix()
+    ))
+}
+
+fn has_commands(room_id: &RoomId) -> bool {
+    commands()
+        .read()
+        .unwrap()
+        .values()
+        .any(|c| c.available_in(room_id))
+}
+
+/// 房间内可用命令的帮助，`name` 为 `None` 时列出所有命令，房间没有可用命令时返回 `None`
+pub fn help_text(room_id: &RoomId, name: Option<&str>) -> Option<String> {
+    let commands = commands().read().unwrap();
+    let visible = commands
+        .values()
+        .filter(|c| c.available_in(room_id))
+        .collect::<Vec<_>>();
+    if visible.is_empty() {
+        return None;
+    }
+
+    let text = match name {
+        Some(name) => match visible.iter().find(|c| c.name == name.to_lowercase()) {
+            Some(command) => command.help_line(),
+            None => format!("未知命令：{}", name),
+        },
+        None => {
+            let mut lines = visible.iter().map(|c| c.help_line()).collect::<Vec<_>>();
+            lines.push(format!("{}help [命令] - 查看帮助", prefix()));
+            lines.join("\n")
+        }
+    };
+    Some(text)
+}
+
+/// 在客户端上注册消息监听，将以前缀开头的消息分发给已注册的命令
+pub fn attach(client: &Client) -> EventHandlerHandle {
+    client.add_event_handler(
+        |event: OriginalSyncRoomMessageEvent,
+         room: matrix_sdk::room::Room,
+         client: matrix_sdk::Client| async move {
+            let matrix_sdk::room::Room::Joined(room) = room else {
+                return;
+            };
+            if client.user_id() == Some(&*event.sender) || is_ignored(&event.sender) {
+                return;
+            }
+            // 只处理 m.text，bot 通常使用 m.notice 发送消息，忽略它们避免互相回复
+            let body = match &event.content.msgtype {
+                MessageType::Text(TextMessageEventContent { body, .. }) => body,
+                _ => return,
+            };
+            let Some(line) = parse::strip_reply_fallback(body)
+                .trim()
+                .strip_prefix(prefix())
+            else {
+                return;
+            };
+
+            let room = Room(room);
+            let mut tokens = match parse::split(line) {
+                Ok(tokens) => tokens.into_iter(),
+                Err(e) => {
+                    // 没有命令的房间里可能是其他 bot 的命令，不回复
+                    if has_commands(room.0.room_id()) {
+                        reply(&room, &event, &format!("命令解析失败: {}", e)).await;
+                    }
+                    return;
+                }
+            };
+            let Some(name) = tokens.next().map(|n| n.to_lowercase()) else {
+                return;
+            };
+            let args = Args::parse(tokens);
+            let room_id = room.0.room_id().to_owned();
+
+            if name == "help" {
+                if let Some(text) = help_text(&room_id, args.get(0)) {
+                    reply(&room, &event, &text).await;
+                }
+                return;
+            }
+
+            let command = commands()
+                .read()
+                .unwrap()
+                .get(&name)
+                .filter(|c| c.available_in(&room_id))
+                .cloned();
+            let Some(command) = command else {
+                log::debug!(
+                    "unknown command {} from {} in {}",
+                    name,
+                    event.sender,
+                    room_id
+                );
+                if let Some(msg) = unknown_reply(&room_id, &name) {
+                    reply(&room, &event, &msg).await;
+                }
+                return;
+            };
+
+            log::info!("command {} from {} in {}", name, event.sender, room_id);
+            // 命令可能耗时较长，避免阻塞 sync
+            tokio::spawn(async move {
+                match command
+                    .access_in(&room_id)
+                    .check(&room, &event.sender)
+                    .await
+                {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        log::warn!("{} denied for command {}", event.sender, name);
+                        let msg =
+                            format!("权限不足：{} 无法使用 {}{}", event.sender, prefix(), name);
+                        reply(&room, &event, &msg).await;
+                        return;
+                    }
+                    Err(e) => {
+                        log::error!("check access for {} failed: {}", event.sender, e);
+                        reply(&room, &event, &format!("权限检查失败: {}", e)).await;
+                        return;
+                    }
+                }
+
+                let ctx = Context {
+                    room: room.clone(),
+                    event: event.clone(),
+                    args,
+                };
+                let result = match command.plugin {
+                    Some(plugin) => crate::plugin::scope(plugin, (command.handler)(ctx)).await,
+                    None => (command.handler)(ctx).await,
+                };
+                if let Err(e) = result {
+                    log::error!("command {} failed: {}", name, e);
+                    reply(&room, &event, &format!("{} 执行失败: {}", name, e)).await;
+                }
+            });
+        },
+    )
+}
+
+async fn reply(room: &Room, event: &OriginalSyncRoomMessageEvent, msg: &str) {
+    if let Err(e) = room
+        .send_relates_msg(msg, event.event_id.as_str(), false)
+        .await
+    {
+        log::error!("send message failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::room_id;
+
+    use super::*;
+
+    #[test]
+    fn test_unknown_reply() {
+        let room_id = room_id!("!unknown_reply:example.org");
+        let empty = room_id!("!unknown_reply_empty:example.org");
+        register(
+            Command::new("unknown_reply_test", |_| async { Ok(()) }).rooms([room_id.to_owned()]),
+        );
+
+        set_reply_unknown(true);
+        assert_eq!(
+            unknown_reply(room_id, "foo").unwrap(),
+            format!("未知命令：foo，发送 {}help 查看可用命令", prefix())
+        );
+        // 没有可用命令的房间不回复
+        assert_eq!(unknown_reply(empty, "foo"), None);
+
+        set_reply_unknown(false);
+        assert_eq!(unknown_reply(room_id, "foo"), None);
+    }
+}
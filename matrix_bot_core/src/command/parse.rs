@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+/// 命令参数，`--name=value` 与 `--name` 为 flag，其余为位置参数
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Args {
+    positional: Vec<String>,
+    flags: HashMap<String, Option<String>>,
+}
+
+impl Args {
+    pub fn parse(tokens: impl IntoIterator<Item = String>) -> Self {
+        let mut args = Args::default();
+        let mut only_positional = false;
+
+        for token in tokens {
+            if only_positional {
+                args.positional.push(token);
+            } else if token == "--" {
+                only_positional = true;
+            } else if let Some(flag) = token.strip_prefix("--").filter(|f| !f.is_empty()) {
+                match flag.split_once('=') {
+                    Some((name, value)) => {
+                        args.flags.insert(name.to_string(), Some(value.to_string()))
+                    }
+                    None => args.flags.insert(flag.to_string(), None),
+                };
+            } else if let Some(flags) = token
+                .strip_prefix('-')
+                .filter(|f| !f.is_empty() && f.chars().all(|c| c.is_ascii_alphabetic()))
+            {
+                for c in flags.chars() {
+                    args.flags.insert(c.to_string(), None);
+                }
+            } else {
+                args.positional.push(token);
+            }
+        }
+
+        args
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.positional.get(index).map(|s| s.as_str())
+    }
+
+    pub fn positional(&self) -> &[String] {
+        &self.positional
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.contains_key(name)
+    }
+
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.flags.get(name).and_then(|v| v.as_deref())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positional.is_empty() && self.flags.is_empty()
+    }
+}
+
+/// 按空白分割，支持单双引号与反斜杠转义
+pub fn split(input: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut quote = None;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', Some('\'')) => current.push(c),
+            ('\\', _) => {
+                let next = chars.next().ok_or(anyhow!("命令以转义符结尾"))?;
+                current.push(next);
+                in_token = true;
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => current.push(c),
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                in_token = true;
+            }
+            (c, None) if c.is_whitespace() => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut current));
+                    in_token = false;
+                }
+            }
+            (c, None) => {
+                current.push(c);
+                in_token = true;
+            }
+        }
+    }
+
+    if let Some(q) = quote {
+        return Err(anyhow!("引号 {} 未闭合", q));
+    }
+    if in_token {
+        tokens.push(current);
+    }
+
+    Ok(tokens)
+}
+
+/// 去除回复消息中 `> ` 开头的引用部分
+pub fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with('>') {
+        return body;
+    }
+
+    let mut rest = body;
+    while rest.starts_with('>') {
+        match rest.split_once('\n') {
+            Some((_, next)) => rest = next,
+            None => return "",
+        }
+    }
+    rest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let tokens = split(r#"download "a b" 'c \d' e\ f"#).unwrap();
+        assert_eq!(tokens, vec!["download", "a b", r"c \d", "e f"]);
+
+        assert!(split(r#"download "a b"#).is_err());
+        assert_eq!(split(r#"x """#).unwrap(), vec!["x", ""]);
+    }
+
+    #[test]
+    fn test_args() {
+        let tokens = split("magnet:?xt=1 --path=/tmp --force -ab -- --raw").unwrap();
+        let args = Args::parse(tokens);
+
+        assert_eq!(args.positional(), ["magnet:?xt=1", "--raw"]);
+        assert_eq!(args.value("path"), Some("/tmp"));
+        assert!(args.flag("force") && args.flag("a") && args.flag("b"));
+        assert_eq!(args.value("force"), None);
+    }
+
+    #[test]
+    fn test_strip_reply_fallback() {
+        let body = "> <@a:b.c> !download xxx\n> more\n\n!status";
+        assert_eq!(strip_reply_fallback(body).trim(), "!status");
+        assert_eq!(strip_reply_fallback("!status"), "!status");
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use serde::de::{
+    self,
+    value::{MapDeserializer, SeqDeserializer},
+    IntoDeserializer, Visitor,
+};
+use toml::{Table, Value};
+
+/// 环境变量覆盖配置的前缀，层级之间使用 `__` 分隔
+///
+/// 例如 `MATRIX_BOT__PLUGINS__WEBHOOK__PORT=8080`，数组元素使用下标：
+/// `MATRIX_BOT__PLUGINS__YANDE_POPULAR__ROOM__0__ROOM_ID=!xxx:server`
+pub const ENV_PREFIX: &str = "MATRIX_BOT__";
+
+/// 将环境变量覆盖到配置上，返回被覆盖的键与环境变量的原始值
+pub fn apply(
+    table: &mut Table,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<BTreeMap<String, String>> {
+    let mut applied = BTreeMap::new();
+    for (key, raw) in vars {
+        let Some(path) = key.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let segments = path
+            .split("__")
+            .map(|s| s.to_lowercase())
+            .collect::<Vec<_>>();
+        if segments.iter().any(|s| s.is_empty()) {
+            return Err(anyhow!("invalid config override {}", key));
+        }
+
+        let Some((first, rest)) = segments.split_first() else {
+            continue;
+        };
+        let entry = table
+            .entry(first.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+        set(entry, rest, &raw).map_err(|e| anyhow!("invalid config override {}: {}", key, e))?;
+        applied.insert(segments.join("."), raw);
+    }
+    Ok(applied)
+}
+
+fn set(target: &mut Value, segments: &[String], raw: &str) -> Result<()> {
+    let Some((first, rest)) = segments.split_first() else {
+        *target = parse_value(target, raw);
+        return Ok(());
+    };
+
+    match target {
+        Value::Table(table) => {
+            let entry = table
+                .entry(first.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+            set(entry, rest, raw)
+        }
+        Value::Array(array) => {
+            let index = first
+                .parse::<usize>()
+                .map_err(|_| anyhow!("`{}` is not an array index", first))?;
+            let len = array.len();
+            let entry = array.get_mut(index).ok_or(anyhow!(
+                "index {} out of range (len {})",
+                index,
+                len
+            ))?;
+            set(entry, rest, raw)
+        }
+        _ => Err(anyhow!("`{}` is not a table", first)),
+    }
+}
+
+// 原值为字符串时保持字符串，否则尝试按 TOML 值解析，例如 `8080`、`true`、`["a", "b"]`
+//
+// 文件中没有该键时无法知道目标类型，反序列化时由 [`Overridden`] 按目标类型修正
+fn parse_value(old: &Value, raw: &str) -> Value {
+    if old.is_str() {
+        return Value::String(raw.to_string());
+    }
+    toml::from_str::<Table>(&format!("v = {}", raw))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+/// 应用了环境变量覆盖的配置，反序列化为字符串时使用环境变量的原始值
+///
+/// 例如 `MATRIX_BOT__CORE__PASSWORD=123456` 被解析为整数，但 `password` 字段需要字符串
+pub(super) struct Overridden<'a> {
+    value: Value,
+    path: String,
+    raw: &'a BTreeMap<String, String>,
+}
+
+impl<'a> Overridden<'a> {
+    pub fn new(table: Table, raw: &'a BTreeMap<String, String>) -> Self {
+        Overridden {
+            value: Value::Table(table),
+            path: String::new(),
+            raw,
+        }
+    }
+
+    fn child(&self, key: &str, value: Value) -> Self {
+        let path = if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.path, key)
+        };
+        Overridden {
+            value,
+            path,
+            raw: self.raw,
+        }
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Overridden<'a> {
+    type Error = toml::de::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.value {
+            Value::Table(ref table) => {
+                let entries = table
+                    .iter()
+                    .map(|(key, value)| (key.clone(), self.child(key, value.clone())))
+                    .collect::<Vec<_>>();
+                let mut map = MapDeserializer::new(entries.into_iter());
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+            Value::Array(ref array) => {
+                let items = array
+                    .iter()
+                    .enumerate()
+                    .map(|(i, value)| self.child(&i.to_string(), value.clone()))
+                    .collect::<Vec<_>>();
+                let mut seq = SeqDeserializer::new(items.into_iter());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            value => value.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.raw.get(&self.path) {
+            Some(raw) => visitor.visit_str(raw),
+            None => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.value.deserialize_enum(name, variants, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de, 'a> IntoDeserializer<'de, toml::de::Error> for Overridden<'a> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let mut table: Table = toml::from_str(
+            r#"
+            [plugins.webhook]
+            token = "abc"
+            port = 1
+
+            [[plugins.yande_popular.room]]
+            room_id = "!a:b.c"
+            "#,
+        )
+        .unwrap();
+
+        let vars = [
+            ("PATH", "/usr/bin"),
+            ("MATRIX_BOT__PLUGINS__WEBHOOK__PORT", "8080"),
+            ("MATRIX_BOT__PLUGINS__WEBHOOK__TOKEN", "123"),
+            (
+                "MATRIX_BOT__PLUGINS__YANDE_POPULAR__ROOM__0__ROOM_ID",
+                "!d:e.f",
+            ),
+            ("MATRIX_BOT__CORE__ADMINS", r#"["@a:b.c"]"#),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+
+        let applied = apply(&mut table, vars).unwrap();
+        assert_eq!(applied.len(), 4);
+
+        let webhook = &table["plugins"]["webhook"];
+        assert_eq!(webhook["port"].as_integer(), Some(8080));
+        assert_eq!(webhook["token"].as_str(), Some("123"));
+        assert_eq!(
+            table["plugins"]["yande_popular"]["room"][0]["room_id"].as_str(),
+            Some("!d:e.f")
+        );
+        assert_eq!(table["core"]["admins"][0].as_str(), Some("@a:b.c"));
+
+        let vars = [("MATRIX_BOT__PLUGINS__YANDE_POPULAR__ROOM__3__ROOM_ID", "x")]
+            .map(|(k, v)| (k.to_string(), v.to_string()));
+        assert!(apply(&mut table, vars).is_err());
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use log::LevelFilter;
+use matrix_sdk::ruma::OwnedUserId;
+use serde::{de::DeserializeOwned, de::DeserializeSeed, Deserialize, Serialize};
+use toml::{Table, Value};
+
+use self::section::Section;
+
+pub mod env;
+mod section;
+
+/// `config.toml` 中的 `[core]` 部分，命令行参数与环境变量优先
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoreConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub homeserver_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// 从文件读取密码，适用于 Docker secrets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+    /// 预先签发的 access token，需要同时设置 `device_id`，`username` 需为完整的用户 ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// 加密数据库与 `session.json` 的口令
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store_passphrase: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store_passphrase_file: Option<PathBuf>,
+    /// 加密导出的房间密钥的恢复密钥文件，不能放在数据目录中
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_key_file: Option<PathBuf>,
+    /// 启用的插件，`all` 表示全部
+    pub plugins: Vec<String>,
+    /// bot 管理员，可以使用所有命令
+    pub admins: Vec<OwnedUserId>,
+    /// 这些用户已交叉签名的设备发起验证时自动确认，不比较表情，无法防范控制了该账号设备的中间人
+    pub verify_allowlist: Vec<OwnedUserId>,
+    pub command_prefix: String,
+    /// 忽略这些用户（例如其他 bot）发送的命令
+    pub ignored_users: Vec<OwnedUserId>,
+    /// 回复未知命令，房间中有其他 bot 时可能互相回复
+    pub reply_unknown_commands: bool,
+    /// 管理房间（ID 或别名），设置后管理命令只能在该房间使用
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_room: Option<String>,
+    /// 转发日志的房间（ID 或别名），不设置则只输出到 stderr
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_room: Option<String>,
+    /// 转发到房间的最低日志级别
+    pub log_room_level: LevelFilter,
+    /// 健康检查服务监听地址，例如 `127.0.0.1:9090`，不设置则不启动
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_listen: Option<SocketAddr>,
+    /// 停止时等待插件退出的秒数，超时后强制中止
+    pub shutdown_timeout: u64,
+    /// `[core.invites]` 邀请处理策略
+    pub invites: InviteConfig,
+}
+
+/// 邀请处理策略，管理员与配置中使用的房间的邀请总是接受
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InviteConfig {
+    /// 接受这些用户的邀请
+    pub users: Vec<OwnedUserId>,
+    /// 接受这些服务器上所有用户的邀请，例如 `example.org`
+    pub servers: Vec<String>,
+    /// 拒绝其他邀请，否则忽略
+    pub reject_others: bool,
+    /// 其他成员都离开后退出房间
+    pub leave_empty: bool,
+}
+
+impl Default for CoreConfig {
+    fn default() -> Self {
+        CoreConfig {
+            homeserver_url: None,
+            username: None,
+            password: None,
+            password_file: None,
+            access_token: None,
+            device_id: None,
+            store_passphrase: None,
+            store_passphrase_file: None,
+            recovery_key_file: None,
+            plugins: vec!["all".to_string()],
+            admins: Vec::new(),
+            verify_allowlist: Vec::new(),
+            command_prefix: crate::command::DEFAULT_PREFIX.to_string(),
+            ignored_users: Vec::new(),
+            reply_unknown_commands: false,
+            admin_room: None,
+            log_room: None,
+            log_room_level: LevelFilter::Warn,
+            health_listen: None,
+            shutdown_timeout: 30,
+            invites: InviteConfig::default(),
+        }
+    }
+}
+
+impl CoreConfig {
+    pub fn plugin_enabled(&self, name: &str) -> bool {
+        self.plugins.iter().any(|p| p == "all" || p == name)
+    }
+}
+
+/// 统一配置文件，包含 `[core]` 与 `[plugins.<name>]`
+#[derive(Debug)]
+pub struct Config {
+    path: PathBuf,
+    // 配置文件的原始文本，没有环境变量覆盖时直接在文本上反序列化，错误信息中保留行号
+    text: String,
+    table: Table,
+    // 被环境变量覆盖的键与原始值
+    overrides: BTreeMap<String, String>,
+    pub core: CoreConfig,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow!("can't read {}: {}", path.as_ref().display(), e))?;
+        Self::parse(path, text, std::env::vars())
+    }
+
+    fn parse(
+        path: impl AsRef<Path>,
+        text: String,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut table =
+            toml::from_str::<Table>(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+        let overrides = env::apply(&mut table, vars)?;
+
+        let mut config = Config {
+            path,
+            text,
+            table,
+            overrides,
+            core: CoreConfig::default(),
+        };
+        config.core = config.section(&["core"])?.unwrap_or_default();
+        Ok(config)
+    }
+
+    /// 重新读取配置文件，`[core]` 保持不变（包含命令行参数的合并结果），修改需要重启才能生效
+    pub fn reload(&self) -> Result<Self> {
+        let mut config = Self::load(&self.path)?;
+        if config.value(&["core"]) != self.value(&["core"]) {
+            log::warn!("changes to [core] need a restart to take effect");
+        }
+        config.core = self.core.clone();
+        Ok(config)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 指定路径的原始值，用于比较配置是否发生变化
+    pub fn value(&self, path: &[&str]) -> Option<&Value> {
+        let (first, rest) = path.split_first()?;
+        rest.iter()
+            .try_fold(self.table.get(*first)?, |value, key| value.get(key))
+    }
+
+    /// 被环境变量覆盖的键
+    pub fn overrides(&self) -> Vec<&str> {
+        self.overrides.keys().map(String::as_str).collect()
+    }
+
+    /// 反序列化指定路径的表，不存在时返回 `None`，没有环境变量覆盖时错误信息中包含行号
+    pub fn section<T: DeserializeOwned>(&self, path: &[&str]) -> Result<Option<T>> {
+        let result = if self.overrides.is_empty() {
+            Section::<T>::new(path).deserialize(toml::Deserializer::new(&self.text))
+        } else {
+            Section::<T>::new(path)
+                .deserialize(env::Overridden::new(self.table.clone(), &self.overrides))
+        };
+        result.map_err(|e| {
+            let source = if self.overrides.is_empty() {
+                self.path.display().to_string()
+            } else {
+                format!("{} (with environment overrides)", self.path.display())
+            };
+            anyhow!("{}: [{}]: {}", source, path.join("."), e)
+        })
+    }
+
+    pub fn plugin<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+        self.section(&["plugins", name])
+    }
+
+    pub fn has_plugin(&self, name: &str) -> bool {
+        self.value(&["plugins", name]).is_some()
+    }
+
+    /// 在配置文件末尾追加插件的默认配置
+    pub fn append_plugin(&self, name: &str, setting: Value) -> Result<()> {
+        let text = plugins_to_string([(name.to_string(), setting)])?;
+        let mut file = std::fs::read_to_string(&self.path)?;
+        if !file.ends_with('\n') {
+            file.push('\n');
+        }
+        file.push('\n');
+        file.push_str(&text);
+        std::fs::write(&self.path, file)?;
+        Ok(())
+    }
+}
+
+/// 生成默认配置文件
+pub fn write_default(
+    path: impl AsRef<Path>,
+    plugins: impl IntoIterator<Item = (String, Value)>,
+) -> Result<()> {
+    let core = CoreConfig {
+        homeserver_url: Some("https://matrix.org".to_string()),
+        username: Some("".to_string()),
+        ..Default::default()
+    };
+    // 保持字段顺序，`[core.invites]` 等子表写在 `[core]` 之后
+    #[derive(Serialize)]
+    struct Root {
+        core: CoreConfig,
+    }
+    let mut text = toml::to_string_pretty(&Root { core })?;
+    text.push('\n');
+    text.push_str(&plugins_to_string(plugins)?);
+
+    if let Some(parent) = path.as_ref().parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, text)?;
+    Ok(())
+}
+
+fn plugins_to_string(plugins: impl IntoIterator<Item = (String, Value)>) -> Result<String> {
+    let mut table = Table::new();
+    table.insert(
+        "plugins".to_string(),
+        Value::Table(plugins.into_iter().collect()),
+    );
+    Ok(toml::to_string_pretty(&table)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Webhook {
+        #[allow(dead_code)]
+        room_id: Vec<String>,
+        port: u16,
+    }
+
+    const TEXT: &str = r#"[core]
+username = "bot"
+plugins = ["webhook"]
+
+[plugins.webhook]
+room_id = ["!a:b.c"]
+port = "abc"
+"#;
+
+    #[test]
+    fn test_section_error_has_line() {
+        let config = Config::parse("config.toml", TEXT.to_string(), []).unwrap();
+        assert_eq!(config.core.username.as_deref(), Some("bot"));
+        assert!(config.core.plugin_enabled("webhook"));
+        assert!(!config.core.plugin_enabled("qbittorrent"));
+        assert!(config.has_plugin("webhook"));
+
+        let err = config.plugin::<Webhook>("webhook").unwrap_err().to_string();
+        assert!(err.contains("line 7"), "{}", err);
+        assert!(config.plugin::<Webhook>("qbittorrent").unwrap().is_none());
+        assert_eq!(
+            config.value(&["plugins", "webhook", "port"]),
+            Some(&Value::String("abc".to_string()))
+        );
+        assert!(config.value(&["plugins", "webhook", "token"]).is_none());
+    }
+
+    #[test]
+    fn test_env_override() {
+        let vars = [("MATRIX_BOT__PLUGINS__WEBHOOK__PORT", "8080")]
+            .map(|(k, v)| (k.to_string(), v.to_string()));
+        let text = TEXT.replace(r#""abc""#, "1");
+        let config = Config::parse("config.toml", text, vars).unwrap();
+        let webhook = config.plugin::<Webhook>("webhook").unwrap().unwrap();
+        assert_eq!(webhook.port, 8080);
+        assert_eq!(config.overrides(), ["plugins.webhook.port"]);
+    }
+
+    #[test]
+    fn test_env_override_string() {
+        // 文件中没有的键按 TOML 解析，目标为字符串时使用原始值
+        let vars = [
+            ("MATRIX_BOT__CORE__PASSWORD", "1e3"),
+            ("MATRIX_BOT__CORE__DEVICE_ID", "123456"),
+            ("MATRIX_BOT__CORE__SHUTDOWN_TIMEOUT", "5"),
+            ("MATRIX_BOT__PLUGINS__WEBHOOK__PORT", "8080"),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let text = TEXT.replace("port = \"abc\"\n", "");
+        let config = Config::parse("config.toml", text, vars).unwrap();
+        assert_eq!(config.core.password.as_deref(), Some("1e3"));
+        assert_eq!(config.core.device_id.as_deref(), Some("123456"));
+        assert_eq!(config.core.shutdown_timeout, 5);
+        let webhook = config.plugin::<Webhook>("webhook").unwrap().unwrap();
+        assert_eq!(webhook.port, 8080);
+    }
+
+    #[test]
+    fn test_invites() {
+        let text = "[core.invites]\nservers = [\"example.org\"]\nreject_others = true\n";
+        let config = Config::parse("config.toml", text.to_string(), []).unwrap();
+        assert_eq!(config.core.invites.servers, ["example.org"]);
+        assert!(config.core.invites.reject_others);
+        assert!(!config.core.invites.leave_empty);
+        assert_eq!(config.core.shutdown_timeout, 30);
+    }
+
+    #[test]
+    fn test_unknown_core_key() {
+        let err = Config::parse("config.toml", "[core]\nuser = 1\n".to_string(), []).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+    }
+}
@@ -0,0 +1,61 @@
+use std::{fmt, marker::PhantomData};
+
+use serde::de::{Deserialize, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor};
+
+/// 只反序列化文档中指定路径的表，其余内容跳过
+///
+/// 可以直接在原始文本上反序列化，错误信息中保留行号
+pub struct Section<'a, T> {
+    path: &'a [&'a str],
+    marker: PhantomData<T>,
+}
+
+impl<'a, T> Section<'a, T> {
+    pub fn new(path: &'a [&'a str]) -> Self {
+        Section {
+            path,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'de, 'a, T: Deserialize<'de>> DeserializeSeed<'de> for Section<'a, T> {
+    type Value = Option<T>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Option<T>, D::Error> {
+        match self.path.split_first() {
+            None => T::deserialize(deserializer).map(Some),
+            Some((key, rest)) => deserializer.deserialize_map(SectionVisitor {
+                key,
+                rest,
+                marker: PhantomData,
+            }),
+        }
+    }
+}
+
+struct SectionVisitor<'a, T> {
+    key: &'a str,
+    rest: &'a [&'a str],
+    marker: PhantomData<T>,
+}
+
+impl<'de, 'a, T: Deserialize<'de>> Visitor<'de> for SectionVisitor<'a, T> {
+    type Value = Option<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a table containing `{}`", self.key)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Option<T>, A::Error> {
+        let mut found = None;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == self.key {
+                found = map.next_value_seed(Section::<T>::new(self.rest))?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(found)
+    }
+}
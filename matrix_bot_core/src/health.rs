@@ -0,0 +1,65 @@
+use std::{net::SocketAddr, time::UNIX_EPOCH};
+
+use anyhow::Result;
+use axum::{
+    http::{header, StatusCode},
+    routing::get,
+    Json, Router,
+};
+use serde_json::{json, Value};
+
+use crate::{metrics, plugin::supervisor};
+
+/// 健康检查与指标服务
+///
+/// - `GET /healthz` 返回 sync 与各插件状态，sync 停止或存在异常插件时返回 503
+/// - `GET /metrics` 返回 Prometheus 文本格式的指标
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/metrics", get(prometheus));
+    log::info!("health check listen on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+async fn healthz() -> (StatusCode, Json<Value>) {
+    let plugins = supervisor::status()
+        .into_iter()
+        .map(|(name, status)| {
+            let since = status.since.elapsed().unwrap_or_default().as_secs();
+            let value = json!({
+                "state": status.state.as_str(),
+                "error": status.state.error(),
+                "since_secs": since,
+                "restarts": status.restarts,
+            });
+            (name.to_string(), value)
+        })
+        .collect::<serde_json::Map<_, _>>();
+
+    let last_sync = metrics::last_sync().and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+    let sync = json!({
+        "alive": metrics::sync_alive(),
+        "last_sync": last_sync.map(|d| d.as_secs()),
+    });
+
+    let (code, status) = if metrics::sync_alive() && supervisor::healthy() {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "degraded")
+    };
+    (
+        code,
+        Json(json!({ "status": status, "sync": sync, "plugins": plugins })),
+    )
+}
+
+async fn prometheus() -> ([(header::HeaderName, &'static str); 1], String) {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+}
@@ -1,2 +1,10 @@
+pub mod command;
+pub mod config;
+pub mod health;
+pub mod logger;
 pub mod matrix;
+pub mod metrics;
+pub mod plugin;
+pub mod template;
+pub use async_trait::async_trait;
 pub use matrix_sdk;
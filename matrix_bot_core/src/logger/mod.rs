@@ -0,0 +1,141 @@
+use std::{
+    collections::BTreeMap,
+    sync::{OnceLock, RwLock},
+};
+
+use anyhow::{anyhow, Result};
+use log::{LevelFilter, Log, Metadata, Record};
+
+pub mod sink;
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// 基于 env_logger 的 logger，支持运行时修改模块的日志级别
+struct Logger {
+    // 只负责输出，过滤由 `filter` 与 `overrides` 决定
+    output: env_logger::Logger,
+    filter: env_logger::filter::Filter,
+    overrides: RwLock<BTreeMap<String, LevelFilter>>,
+}
+
+impl Logger {
+    fn level_for(&self, target: &str) -> Option<LevelFilter> {
+        // 最长前缀优先
+        self.overrides
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(module, _)| {
+                target == module.as_str()
+                    || target
+                        .strip_prefix(module.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .map(|(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        let overrides = self.overrides.read().unwrap();
+        overrides
+            .values()
+            .copied()
+            .fold(self.filter.filter(), Ord::max)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match self.level_for(metadata.target()) {
+            Some(level) => metadata.level() <= level,
+            None => self.filter.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.output.log(record);
+            sink::forward(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.output.flush();
+    }
+}
+
+/// 初始化全局 logger，`RUST_LOG` 之后应用 `defaults`
+pub fn init(defaults: &[(Option<&str>, LevelFilter)]) -> Result<()> {
+    let mut filter = env_logger::filter::Builder::new();
+    if let Ok(env) = std::env::var("RUST_LOG") {
+        filter.parse(&env);
+    }
+    for (module, level) in defaults {
+        filter.filter(*module, *level);
+    }
+
+    let logger = Logger {
+        output: env_logger::Builder::new()
+            .filter_level(LevelFilter::Trace)
+            .build(),
+        filter: filter.build(),
+        overrides: RwLock::new(BTreeMap::new()),
+    };
+    LOGGER
+        .set(logger)
+        .map_err(|_| anyhow!("logger already initialized"))?;
+    let logger = get()?;
+    log::set_logger(logger)?;
+    log::set_max_level(logger.max_level());
+    Ok(())
+}
+
+fn get() -> Result<&'static Logger> {
+    LOGGER.get().ok_or(anyhow!("logger not initialized"))
+}
+
+/// 设置模块的日志级别，覆盖启动时的配置，`None` 表示恢复默认
+pub fn set_level(module: &str, level: Option<LevelFilter>) -> Result<()> {
+    let logger = get()?;
+    {
+        let mut overrides = logger.overrides.write().unwrap();
+        match level {
+            Some(level) => overrides.insert(module.to_string(), level),
+            None => overrides.remove(module),
+        };
+    }
+    log::set_max_level(logger.max_level());
+    Ok(())
+}
+
+/// 运行时设置的模块日志级别
+pub fn levels() -> BTreeMap<String, LevelFilter> {
+    get()
+        .map(|logger| logger.overrides.read().unwrap().clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_for() {
+        let logger = Logger {
+            output: env_logger::Builder::new().build(),
+            filter: env_logger::filter::Builder::new().build(),
+            overrides: RwLock::new(BTreeMap::from([
+                ("webhook".to_string(), LevelFilter::Debug),
+                ("webhook::setting".to_string(), LevelFilter::Trace),
+            ])),
+        };
+        assert_eq!(logger.level_for("webhook"), Some(LevelFilter::Debug));
+        assert_eq!(logger.level_for("webhook::x"), Some(LevelFilter::Debug));
+        assert_eq!(
+            logger.level_for("webhook::setting"),
+            Some(LevelFilter::Trace)
+        );
+        assert_eq!(logger.level_for("webhooks"), None);
+        assert_eq!(logger.max_level(), LevelFilter::Trace);
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use log::{Level, LevelFilter, Record};
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+use crate::matrix::{client::Client, room::Room};
+
+// 两次发送之间的最短间隔，同时也是合并日志的时间窗口
+const BATCH_INTERVAL: Duration = Duration::from_secs(30);
+// 同一条日志在该时间内只发送一次
+const DEDUP_WINDOW: Duration = Duration::from_secs(10 * 60);
+// 单条消息最多包含的日志条数
+const MAX_LINES: usize = 20;
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+tokio::task_local! {
+    // 发送日志消息期间产生的日志不再转发，避免递归
+    static SENDING: ();
+}
+
+struct Sink {
+    level: LevelFilter,
+    tx: mpsc::UnboundedSender<Entry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Entry {
+    level: Level,
+    target: String,
+    message: String,
+}
+
+/// 由 logger 调用，将满足级别的日志交给发送任务
+pub(super) fn forward(record: &Record) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    if record.level() > sink.level
+        || record.target().starts_with(module_path!())
+        || SENDING.try_with(|_| ()).is_ok()
+    {
+        return;
+    }
+    let _ = sink.tx.send(Entry {
+        level: record.level(),
+        target: record.target().to_string(),
+        message: record.args().to_string(),
+    });
+}
+
+/// 将 `level` 及以上的日志合并、去重后发送到 `room`，`shutdown` 取消后发送剩余日志并退出
+pub fn start(
+    client: &Client,
+    room: &str,
+    level: LevelFilter,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    let client = client.clone();
+    let room = room.to_string();
+    tokio::spawn(async move {
+        let room = match Room::new(&client, &room).await {
+            Ok(room) => room,
+            Err(e) => {
+                log::error!("log room {} unavailable: {}", room, e);
+                return;
+            }
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        if SINK.set(Sink { level, tx }).is_err() {
+            log::error!("log sink already started");
+            return;
+        }
+        log::info!("forward {} logs to {}", level, room.0.room_id());
+        run(room, rx, shutdown).await;
+    })
+}
+
+async fn run(room: Room, mut rx: mpsc::UnboundedReceiver<Entry>, shutdown: CancellationToken) {
+    let mut batch = Batch::default();
+    let mut interval = tokio::time::interval(BATCH_INTERVAL);
+    loop {
+        tokio::select! {
+            Some(entry) = rx.recv() => batch.push(entry),
+            _ = interval.tick() => send(&room, &mut batch).await,
+            _ = shutdown.cancelled() => {
+                while let Ok(entry) = rx.try_recv() {
+                    batch.push(entry);
+                }
+                send(&room, &mut batch).await;
+                return;
+            }
+        }
+    }
+}
+
+async fn send(room: &Room, batch: &mut Batch) {
+    let Some(text) = batch.take() else {
+        return;
+    };
+    // 直接发送而不经过发送队列，失败时只输出到 stderr
+    let content = RoomMessageEventContent::text_plain(text);
+    let result = SENDING.scope((), room.0.send(content, None)).await;
+    if let Err(e) = result {
+        log::error!("send logs failed: {}", e);
+    }
+}
+
+#[derive(Default)]
+struct Batch {
+    // 按首次出现顺序保存，值为出现次数
+    entries: Vec<(Entry, usize)>,
+    sent: HashMap<Entry, Instant>,
+    suppressed: usize,
+}
+
+impl Batch {
+    fn push(&mut self, entry: Entry) {
+        if let Some((_, count)) = self.entries.iter_mut().find(|(e, _)| *e == entry) {
+            *count += 1;
+            return;
+        }
+        if self
+            .sent
+            .get(&entry)
+            .is_some_and(|at| at.elapsed() < DEDUP_WINDOW)
+        {
+            self.suppressed += 1;
+            return;
+        }
+        self.entries.push((entry, 1));
+    }
+
+    fn take(&mut self) -> Option<String> {
+        if self.entries.is_empty() {
+            return None;
+        }
+
+        let now = Instant::now();
+        self.sent
+            .retain(|_, at| now.duration_since(*at) < DEDUP_WINDOW);
+
+        let total = self.entries.len();
+        let mut lines = Vec::new();
+        for (entry, count) in self.entries.drain(..) {
+            if lines.len() < MAX_LINES {
+                let mut line = format!("[{}] {}: {}", entry.level, entry.target, entry.message);
+                if count > 1 {
+                    line.push_str(&format!(" (×{})", count));
+                }
+                lines.push(line);
+            }
+            self.sent.insert(entry, now);
+        }
+        if total > MAX_LINES {
+            lines.push(format!("……另有 {} 条日志未显示", total - MAX_LINES));
+        }
+        if self.suppressed > 0 {
+            lines.push(format!(
+                "{} 分钟内重复的 {} 条日志已忽略",
+                DEDUP_WINDOW.as_secs() / 60,
+                self.suppressed
+            ));
+            self.suppressed = 0;
+        }
+        Some(lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(message: &str) -> Entry {
+        Entry {
+            level: Level::Error,
+            target: "webhook".to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_batch_dedup() {
+        let mut batch = Batch::default();
+        assert!(batch.take().is_none());
+
+        batch.push(entry("a"));
+        batch.push(entry("b"));
+        batch.push(entry("a"));
+        assert_eq!(
+            batch.take().unwrap(),
+            "[ERROR] webhook: a (×2)\n[ERROR] webhook: b"
+        );
+
+        // 已发送过的日志在时间窗口内被忽略
+        batch.push(entry("a"));
+        assert!(batch.take().is_none());
+        batch.push(entry("c"));
+        assert_eq!(
+            batch.take().unwrap(),
+            "[ERROR] webhook: c\n10 分钟内重复的 1 条日志已忽略"
+        );
+    }
+}
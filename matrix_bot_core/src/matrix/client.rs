@@ -1,10 +1,26 @@
-use std::{fs, ops::Deref, path::Path};
+use std::{
+    fs,
+    io::IsTerminal,
+    ops::Deref,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::Result;
-use matrix_sdk::{self, config::SyncSettings};
+use anyhow::{anyhow, bail, Result};
+use matrix_sdk::{
+    self,
+    config::SyncSettings,
+    ruma::{api::client::error::ErrorKind, OwnedDeviceId, OwnedUserId},
+    HttpError, Session,
+};
 
 use url::Url;
 
+use super::{
+    e2ee,
+    session::{self, Loaded},
+};
+
 #[derive(Debug, Clone)]
 pub struct Client(pub matrix_sdk::Client);
 
@@ -19,88 +35,282 @@ impl Deref for Client {
     }
 }
 
+/// 登录方式
+#[derive(Debug, Clone, Copy)]
+pub enum Auth<'a> {
+    /// 用户名密码登录，已有 `session.json` 时优先恢复会话
+    Password {
+        username: &'a str,
+        password: &'a str,
+    },
+    /// 需要重新登录时在终端输入密码，密码不会被保存
+    Interactive { username: &'a str },
+    /// 预先签发的 access token，`user_id` 需要是完整的用户 ID
+    AccessToken {
+        user_id: &'a str,
+        device_id: &'a str,
+        access_token: &'a str,
+    },
+}
+
+impl Auth<'_> {
+    fn username(&self) -> &str {
+        match self {
+            Auth::Password { username, .. } | Auth::Interactive { username } => username,
+            Auth::AccessToken { user_id, .. } => user_id,
+        }
+    }
+
+    /// 获取密码，未设置密码时在终端输入
+    pub async fn password(&self) -> Result<String> {
+        match *self {
+            Auth::Password { password, .. } => Ok(password.to_string()),
+            Auth::Interactive { username }
+            | Auth::AccessToken {
+                user_id: username, ..
+            } => {
+                if !std::io::stdin().is_terminal() {
+                    bail!(
+                        "password is not set and stdin is not a terminal, \
+                         set PASSWORD / PASSWORD_FILE or run `matrix_bot login` once"
+                    );
+                }
+                let prompt = format!("Password for {}: ", username);
+                let password =
+                    tokio::task::spawn_blocking(move || rpassword::prompt_password(prompt))
+                        .await??;
+                Ok(password)
+            }
+        }
+    }
+}
+
+/// 网络错误时首次同步的最大重试次数
+const SYNC_RETRIES: u32 = 8;
+
 impl Client {
+    /// 登录并完成首次同步，`passphrase` 用于加密数据库与 `session.json`
     pub async fn login(
         homeserver_url: &str,
-        username: &str,
-        password: &str,
+        auth: Auth<'_>,
+        passphrase: Option<&str>,
         session_file: impl AsRef<Path>,
         db_path: impl AsRef<Path>,
     ) -> Result<Client> {
         let homeserver_url = Url::parse(homeserver_url).expect("Couldn't parse the homeserver URL");
+        let session_file = session_file.as_ref();
+        let data = session_file.parent().unwrap();
 
         std::fs::create_dir_all(&db_path)?;
-        std::fs::create_dir_all(session_file.as_ref().parent().unwrap())?;
+        std::fs::create_dir_all(data)?;
+
+        let client = if let Auth::AccessToken {
+            user_id,
+            device_id,
+            access_token,
+        } = auth
+        {
+            // token 与设备绑定，直接恢复会话，不重建设备
+            let client = Self::build(&homeserver_url, &db_path, passphrase).await?;
+            Self::login_token(&client, user_id, device_id, access_token).await?;
+            Self::sync_once(&client).await?;
+            client
+        } else {
+            // 先读取会话再打开数据库，避免用口令打开未加密的旧数据库
+            let restored = match session::load(session_file, passphrase)? {
+                Loaded::Session(session) => {
+                    let client = Self::build(&homeserver_url, &db_path, passphrase).await?;
+                    // 数据库与会话不匹配等本地错误需要人工处理，不自动重置
+                    client.restore_login(session).await.map_err(|e| {
+                        log::error!("restore login error: {}", e);
+                        e
+                    })?;
+                    match Self::sync_once(&client).await {
+                        Ok(_) => {
+                            log::info!("Restored login from session file");
+                            Ok(client)
+                        }
+                        Err(e) if is_invalid_session(&e) => {
+                            // 重置前导出旧设备的房间密钥，新设备登录后导入
+                            if let Err(e) = e2ee::export_keys(&client, data).await {
+                                log::error!("export room keys failed: {}", e);
+                            }
+                            Err(format!("the server rejected the stored session: {}", e))
+                        }
+                        // 网络等其他错误不能说明会话失效，保留数据库直接退出
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Loaded::Unencrypted(session) => {
+                    // 用旧会话打开未加密的数据库导出密钥，重新登录后导入
+                    let client = Self::build(&homeserver_url, &db_path, None).await?;
+                    client.restore_login(session).await?;
+                    if let Err(e) = e2ee::export_keys(&client, data).await {
+                        log::error!("export room keys failed: {}", e);
+                    }
+                    Err(
+                        "store passphrase is set but the existing store is not encrypted"
+                            .to_string(),
+                    )
+                }
+                Loaded::Relogin(reason) => Err(reason),
+            };
 
-        let mut client = matrix_sdk::Client::builder()
-            .homeserver_url(&homeserver_url)
-            .sled_store(&db_path, None)?
+            match restored {
+                Ok(client) => client,
+                Err(reason) => {
+                    Self::relogin(
+                        auth,
+                        passphrase,
+                        &reason,
+                        &homeserver_url,
+                        session_file,
+                        &db_path,
+                    )
+                    .await?
+                }
+            }
+        };
+
+        log::info!("Logged in as {}", auth.username());
+        Ok(Client(client))
+    }
+
+    /// 备份旧数据库后用密码重新登录，会创建新设备
+    async fn relogin(
+        auth: Auth<'_>,
+        passphrase: Option<&str>,
+        reason: &str,
+        homeserver_url: &Url,
+        session_file: &Path,
+        db_path: impl AsRef<Path>,
+    ) -> Result<matrix_sdk::Client> {
+        let password = auth.password().await?;
+        if let Some(backup) = backup_store(&db_path)? {
+            log::warn!(
+                "{}, logging in as a new device. the old store with its encryption keys \
+                 was moved to {}, messages encrypted for the old device can't be \
+                 decrypted unless the keys are imported again",
+                reason,
+                backup.display()
+            );
+        } else {
+            log::info!("{}, logging in as a new device", reason);
+        }
+        let client = Self::build(homeserver_url, &db_path, passphrase).await?;
+        client
+            .login_username(auth.username(), &password)
+            .initial_device_display_name("matrix_bot")
+            .send()
+            .await?;
+        if let Some(session) = client.session() {
+            session::save(session_file, &session, passphrase)?;
+        }
+        Self::sync_once(&client).await?;
+
+        if let Err(e) = e2ee::bootstrap_cross_signing(&client, &password).await {
+            log::error!("bootstrap cross-signing failed: {}", e);
+        }
+        if let Err(e) = e2ee::import_keys(&client, session_file.parent().unwrap()).await {
+            log::error!("import room keys failed: {}", e);
+        }
+        Ok(client)
+    }
+
+    async fn build(
+        homeserver_url: &Url,
+        db_path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> Result<matrix_sdk::Client> {
+        let client = matrix_sdk::Client::builder()
+            .homeserver_url(homeserver_url)
+            .sled_store(db_path, passphrase)?
             .build()
             .await
             .map_err(|e| {
                 log::error!("client build error: {}", e);
                 e
             })?;
+        Ok(client)
+    }
 
-        if !client.logged_in() {
-            if session_file.as_ref().exists()
-                && Self::restore_login(&client, &session_file).await.is_ok()
-                && client.logged_in()
-                && client.sync_once(SyncSettings::new()).await.is_ok()
-            {
-                log::info!("Restored login from session file");
-            } else {
-                drop(client);
-                // 清理数据库
-                fs::remove_dir_all(&db_path)?;
-                client = matrix_sdk::Client::builder()
-                    .homeserver_url(&homeserver_url)
-                    .sled_store(db_path, None)?
-                    .build()
-                    .await
-                    .map_err(|e| {
-                        log::error!("client build error: {}", e);
-                        e
-                    })?;
-                Self::login_username(&client, &session_file, username, password).await?;
-                client.sync_once(SyncSettings::new()).await?;
-            };
-
-            log::info!("Logged in as {}", username);
+    /// 首次同步，网络错误与服务器临时错误时退避重试
+    async fn sync_once(client: &matrix_sdk::Client) -> matrix_sdk::Result<()> {
+        let mut attempt = 0;
+        loop {
+            match client.sync_once(SyncSettings::new()).await {
+                Ok(_) => return Ok(()),
+                Err(e) if is_transient(&e) && attempt < SYNC_RETRIES => {
+                    let delay = Duration::from_secs(1 << attempt.min(6));
+                    attempt += 1;
+                    log::warn!(
+                        "sync failed: {}, retry {}/{} in {}s",
+                        e,
+                        attempt,
+                        SYNC_RETRIES,
+                        delay.as_secs()
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e),
+            }
         }
-
-        Ok(Client(client))
     }
 
-    async fn restore_login(
+    async fn login_token(
         client: &matrix_sdk::Client,
-        session_file: impl AsRef<Path>,
+        user_id: &str,
+        device_id: &str,
+        access_token: &str,
     ) -> Result<()> {
-        let session = fs::read_to_string(session_file)?;
-        let session = serde_json::from_str(&session)?;
+        let user_id = OwnedUserId::try_from(user_id)
+            .map_err(|e| anyhow!("access token login needs a full user id: {}", e))?;
+        let session = Session {
+            access_token: access_token.to_string(),
+            refresh_token: None,
+            user_id,
+            device_id: OwnedDeviceId::from(device_id),
+        };
         client.restore_login(session).await.map_err(|e| {
-            log::error!("restore login error: {}", e);
+            log::error!("access token login error: {}", e);
             e
         })?;
         Ok(())
     }
+}
+
+/// 服务器明确表示 token 失效，需要重新登录
+fn is_invalid_session(e: &matrix_sdk::Error) -> bool {
+    matches!(
+        e.client_api_error_kind(),
+        Some(ErrorKind::UnknownToken { .. } | ErrorKind::MissingToken)
+    )
+}
+
+fn is_transient(e: &matrix_sdk::Error) -> bool {
+    match e {
+        matrix_sdk::Error::Http(HttpError::Reqwest(_)) => true,
+        matrix_sdk::Error::Http(HttpError::Server(status)) => status.is_server_error(),
+        e => matches!(
+            e.client_api_error_kind(),
+            Some(ErrorKind::LimitExceeded { .. })
+        ),
+    }
+}
 
-    async fn login_username(
-        client: &matrix_sdk::Client,
-        session_file: impl AsRef<Path>,
-        username: &str,
-        password: &str,
-    ) -> Result<()> {
-        client
-            .login_username(username, password)
-            .initial_device_display_name("matrix_bot")
-            .send()
-            .await?;
-        let session = client.session();
-        if let Some(session) = session {
-            let session = serde_json::to_string(&session)?;
-            fs::write(session_file, session)?;
-        };
-        Ok(())
+/// 重置前备份数据库，返回备份路径，数据库为空时不备份
+fn backup_store(db_path: impl AsRef<Path>) -> Result<Option<PathBuf>> {
+    let db_path = db_path.as_ref();
+    if fs::read_dir(db_path).map_or(true, |mut dir| dir.next().is_none()) {
+        return Ok(None);
     }
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut backup = db_path.as_os_str().to_owned();
+    backup.push(format!(".bak-{}", secs));
+    let backup = PathBuf::from(backup);
+    fs::rename(db_path, &backup)?;
+    fs::create_dir_all(db_path)?;
+    Ok(Some(backup))
 }
@@ -0,0 +1,193 @@
+//! SAS 验证的确认渠道：本地 HTTP / 命令行、管理房间中的回应或命令，以及白名单自动确认
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Mutex, OnceLock, RwLock},
+    time::Duration,
+};
+
+use matrix_sdk::{
+    encryption::verification::{format_emojis, SasVerification},
+    ruma::{
+        events::reaction::OriginalSyncReactionEvent, DeviceId, EventId, OwnedEventId, OwnedUserId,
+        RoomId, UserId,
+    },
+};
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::{command, matrix::room::Room};
+
+/// 等待确认的最长时间
+const TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+struct Pending {
+    description: String,
+    emojis: String,
+    /// 管理房间中的验证消息
+    event_id: Option<OwnedEventId>,
+    tx: oneshot::Sender<bool>,
+}
+
+static PENDING: OnceLock<Mutex<HashMap<String, Pending>>> = OnceLock::new();
+static ROOM: OnceLock<Room> = OnceLock::new();
+static ALLOWLIST: OnceLock<RwLock<HashSet<OwnedUserId>>> = OnceLock::new();
+
+fn pending() -> &'static Mutex<HashMap<String, Pending>> {
+    PENDING.get_or_init(Default::default)
+}
+
+fn allowlist() -> &'static RwLock<HashSet<OwnedUserId>> {
+    ALLOWLIST.get_or_init(Default::default)
+}
+
+/// 设置管理房间，验证表情会发送到该房间等待管理员确认
+pub fn set_room(room: Room) {
+    if ROOM.set(room).is_err() {
+        log::warn!("verification room is already set");
+    }
+}
+
+/// 白名单内用户已交叉签名的设备发起的验证自动确认，不比较表情
+///
+/// 只检查设备是否由用户的交叉签名身份签名：能够控制该账号的已签名设备或其交叉签名密钥时，
+/// 仍可以冒充对方完成验证
+pub fn set_allowlist(users: impl IntoIterator<Item = OwnedUserId>) {
+    *allowlist().write().unwrap() = users.into_iter().collect();
+}
+
+/// 确认或取消验证，验证码不存在时返回 `false`
+pub fn confirm(code: &str, accept: bool) -> bool {
+    let pending = pending().lock().unwrap().remove(code);
+    match pending {
+        Some(pending) => pending.tx.send(accept).is_ok(),
+        None => false,
+    }
+}
+
+/// 通过管理房间中的验证消息确认或取消
+pub fn confirm_event(event_id: &EventId, accept: bool) -> bool {
+    let code = pending()
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, p)| p.event_id.as_deref() == Some(event_id))
+        .map(|(code, _)| code.clone());
+    code.map_or(false, |code| confirm(&code, accept))
+}
+
+/// 等待确认的验证：验证码、对方设备与表情
+pub fn list() -> Vec<(String, String, String)> {
+    pending()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(code, p)| (code.clone(), p.description.clone(), p.emojis.clone()))
+        .collect()
+}
+
+/// 管理员在管理房间中对验证消息的回应
+pub(super) fn on_reaction(ev: &OriginalSyncReactionEvent, room_id: &RoomId) {
+    let in_room = ROOM.get().map_or(false, |room| room.0.room_id() == room_id);
+    if !in_room || !command::is_admin(&ev.sender) {
+        return;
+    }
+    let accept = match ev.content.relates_to.key.trim_end_matches('\u{fe0f}') {
+        "✅" | "👍" => true,
+        "❌" | "👎" => false,
+        _ => return,
+    };
+    if confirm_event(&ev.content.relates_to.event_id, accept) {
+        log::info!("verification answered by {} with a reaction", ev.sender);
+    }
+}
+
+fn is_allowlisted(user_id: &UserId) -> bool {
+    allowlist().read().unwrap().contains(user_id)
+}
+
+/// 设备是否已被其所有者的交叉签名身份签名
+async fn is_cross_signed(
+    client: &matrix_sdk::Client,
+    user_id: &UserId,
+    device_id: &DeviceId,
+) -> bool {
+    match client.encryption().get_device(user_id, device_id).await {
+        Ok(Some(device)) => device.is_cross_signed_by_owner(),
+        Ok(None) => false,
+        Err(e) => {
+            log::warn!("get device {} {} failed: {}", user_id, device_id, e);
+            false
+        }
+    }
+}
+
+/// 等待任一渠道确认，超时视为取消
+pub(super) async fn wait(client: &matrix_sdk::Client, sas: &SasVerification, port: u16) -> bool {
+    let device = sas.other_device();
+    let description = format!("{} {}", device.user_id(), device.device_id());
+    if is_allowlisted(device.user_id()) {
+        let key = device
+            .ed25519_key()
+            .map_or("unknown".to_string(), |key| key.to_base64());
+        if is_cross_signed(client, device.user_id(), device.device_id()).await {
+            log::warn!(
+                "auto confirmed verification with {} (ed25519 {})",
+                description,
+                key
+            );
+            return true;
+        }
+        log::warn!(
+            "{} (ed25519 {}) is not cross-signed by its owner, waiting for manual confirmation",
+            description,
+            key
+        );
+    }
+
+    let emojis = match sas.emoji() {
+        Some(emoji) => format_emojis(emoji),
+        None => return false,
+    };
+    let code = Uuid::new_v4().simple().to_string()[..8].to_string();
+    let (tx, rx) = oneshot::channel();
+    pending().lock().unwrap().insert(
+        code.clone(),
+        Pending {
+            description: description.clone(),
+            emojis: emojis.clone(),
+            event_id: None,
+            tx,
+        },
+    );
+
+    println!("\nDo the emojis match: \n{}", emojis);
+    println!("Please run one of the commands to allow:");
+    println!(
+        "local: wget  -O - http://127.0.0.1:{}/verify/{}",
+        port, code
+    );
+    println!("cli:   matrix_bot verify confirm {}", code);
+
+    if let Some(room) = ROOM.get() {
+        let msg = format!(
+            "{} 请求验证，请确认表情是否一致：\n{}\n用 ✅ 或 ❌ 回应此消息，或发送 {}verify confirm {}",
+            description,
+            emojis,
+            command::prefix(),
+            code
+        );
+        match room.send_msg(&msg, false).await {
+            Ok(event_id) => {
+                if let Some(pending) = pending().lock().unwrap().get_mut(&code) {
+                    pending.event_id = Some(event_id);
+                }
+            }
+            Err(e) => log::error!("send verification to admin room failed: {}", e),
+        }
+    }
+
+    let accepted = matches!(tokio::time::timeout(TIMEOUT, rx).await, Ok(Ok(true)));
+    pending().lock().unwrap().remove(&code);
+    accepted
+}
@@ -1,8 +1,12 @@
+use std::{fs, path::Path, sync::OnceLock, time::Duration};
+
+use anyhow::{anyhow, Result};
 use matrix_sdk::{
     self,
-    encryption::verification::{format_emojis, SasVerification, Verification},
+    encryption::verification::{SasVerification, Verification},
     event_handler::EventHandlerHandle,
     ruma::{
+        api::client::uiaa,
         events::{
             key::verification::{
                 done::{OriginalSyncKeyVerificationDoneEvent, ToDeviceKeyVerificationDoneEvent},
@@ -10,15 +14,33 @@ use matrix_sdk::{
                 request::ToDeviceKeyVerificationRequestEvent,
                 start::{OriginalSyncKeyVerificationStartEvent, ToDeviceKeyVerificationStartEvent},
             },
+            reaction::OriginalSyncReactionEvent,
             room::message::{MessageType, OriginalSyncRoomMessageEvent},
         },
         UserId,
     },
 };
 use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
 use super::client::Client;
 
+pub mod confirm;
+
+/// 数据目录中的房间密钥导出文件，用恢复密钥加密
+///
+/// 这不是服务器端密钥备份：文件与数据目录一起丢失时无法恢复。matrix-sdk 0.6 没有
+/// 密钥备份 API，服务器端备份需要升级 matrix-sdk 后实现
+pub const KEYS_FILE: &str = "room_keys.txt";
+// 旧版本保存在数据目录中的恢复密钥，不再读取
+const LEGACY_RECOVERY_KEY_FILE: &str = "recovery_key";
+const KEYS_BACKUP_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+static RECOVERY_KEY: OnceLock<String> = OnceLock::new();
+/// 数据目录中记录本地确认服务端口的文件，供命令行使用
+pub const PORT_FILE: &str = "verify.port";
+
 mod web_server {
     use std::{
         net::{SocketAddr, TcpListener},
@@ -26,84 +48,72 @@ mod web_server {
     };
 
     use axum::{extract::Path, http::StatusCode, routing::get, Router};
-    use tokio::sync::broadcast;
+    use tokio_util::sync::CancellationToken;
 
-    pub static TX: OnceLock<broadcast::Sender<String>> = OnceLock::new();
-    pub static PORT: OnceLock<u16> = OnceLock::new();
+    use super::confirm;
 
-    pub async fn axum_verify_server() {
-        TX.get_or_init(|| {
-            let (tx, _) = broadcast::channel(64);
-            tx
-        });
+    pub static PORT: OnceLock<u16> = OnceLock::new();
 
-        let port = PORT.get_or_init(|| {
+    pub fn port() -> u16 {
+        *PORT.get_or_init(|| {
             let listener = TcpListener::bind("127.0.0.1:0").unwrap();
             listener.local_addr().unwrap().port()
-        });
+        })
+    }
 
-        let app: Router = Router::new().route("/verify/:code", get(verify_code));
-        let addr = SocketAddr::from(([127, 0, 0, 1], *port));
+    pub async fn axum_verify_server(shutdown: CancellationToken) {
+        let app: Router = Router::new()
+            .route("/verify", get(list))
+            .route("/verify/:code", get(verify_code))
+            .route("/verify/:code/cancel", get(cancel_code));
+        let addr = SocketAddr::from(([127, 0, 0, 1], port()));
         let server = axum::Server::bind(&addr)
             .serve(app.into_make_service())
-            .with_graceful_shutdown(async move {
-                tokio::signal::ctrl_c()
-                    .await
-                    .expect("failed to install CTRL+C handler")
-            });
+            .with_graceful_shutdown(shutdown.cancelled_owned());
 
         server.await.unwrap();
     }
 
+    async fn list() -> String {
+        confirm::list()
+            .into_iter()
+            .map(|(code, description, emojis)| format!("{}  {}\n{}\n", code, description, emojis))
+            .collect()
+    }
+
     async fn verify_code(Path(code): Path<String>) -> StatusCode {
-        let result = TX.get().unwrap().send(code);
+        answer(&code, true)
+    }
 
-        if result.is_err() {
-            return StatusCode::INTERNAL_SERVER_ERROR;
-        }
+    async fn cancel_code(Path(code): Path<String>) -> StatusCode {
+        answer(&code, false)
+    }
 
-        StatusCode::OK
+    fn answer(code: &str, accept: bool) -> StatusCode {
+        if confirm::confirm(code, accept) {
+            StatusCode::OK
+        } else {
+            StatusCode::NOT_FOUND
+        }
     }
 }
 
 async fn wait_for_confirmation(client: matrix_sdk::Client, sas: SasVerification) {
-    let emoji = sas.emoji().expect("The emoji should be available now");
-
-    println!("\nDo the emojis match: \n{}", format_emojis(emoji));
-
-    let code = uuid::Uuid::new_v4().simple().to_string();
+    if confirm::wait(&client, &sas, web_server::port()).await {
+        println!("Code matches");
+        let _ = sas.confirm().await;
 
-    println!("Please run the command to allow:");
-    println!(
-        "local: wget  -O - http://127.0.0.1:{}/verify/{}",
-        web_server::PORT.get().unwrap(),
-        code
-    );
-
-    // 最多等待 5 分钟
-    let timeout = std::time::Instant::now();
-    let mut rx = web_server::TX
-        .get_or_init(|| {
-            let (tx, _) = tokio::sync::broadcast::channel(64);
-            tx
-        })
-        .subscribe();
-
-    while timeout.elapsed().as_secs() < 5 * 60 {
-        if let Ok(msg) = rx.try_recv() {
-            log::warn!("msg: {}", msg);
-            if msg == code {
-                println!("Code matches");
-                let _ = sas.confirm().await;
-
-                if sas.is_done() {
-                    print_result(&sas);
-                    print_devices(sas.other_device().user_id(), &client).await;
-                }
-                break;
-            }
+        if sas.is_done() {
+            print_result(&sas);
+            print_devices(sas.other_device().user_id(), &client).await;
         }
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    } else {
+        log::info!(
+            "verification with {} {} cancelled",
+            sas.other_device().user_id(),
+            sas.other_device().device_id()
+        );
+        let _ = sas.cancel().await;
     }
 }
 
@@ -118,7 +128,8 @@ fn print_result(sas: &SasVerification) {
     );
 }
 
-async fn print_devices(user_id: &UserId, client: &matrix_sdk::Client) {
+/// 输出用户的设备及验证状态
+pub async fn print_devices(user_id: &UserId, client: &matrix_sdk::Client) {
     println!("Devices of user {}", user_id);
     let clients = client.encryption().get_user_devices(user_id).await;
 
@@ -134,7 +145,12 @@ async fn print_devices(user_id: &UserId, client: &matrix_sdk::Client) {
     }
 }
 
-pub fn sync(client: &Client) -> matrix_sdk::Result<(Vec<EventHandlerHandle>, JoinHandle<()>)> {
+/// 注册验证相关的事件处理，并启动本地确认服务，端口写入 `data` 下的 [`PORT_FILE`]，`shutdown` 取消后服务停止
+pub fn sync(
+    client: &Client,
+    data: &Path,
+    shutdown: CancellationToken,
+) -> Result<(Vec<EventHandlerHandle>, JoinHandle<()>)> {
     let client = client.clone();
     let mut handlers = Vec::new();
     handlers.push(client.add_event_handler(
@@ -309,7 +325,146 @@ pub fn sync(client: &Client) -> matrix_sdk::Result<(Vec<EventHandlerHandle>, Joi
         },
     ));
 
-    let handler = tokio::spawn(web_server::axum_verify_server());
+    handlers.push(client.add_event_handler(
+        |ev: OriginalSyncReactionEvent, room: matrix_sdk::room::Room| async move {
+            confirm::on_reaction(&ev, room.room_id());
+        },
+    ));
+
+    fs::write(data.join(PORT_FILE), web_server::port().to_string())?;
+    let handler = tokio::spawn(web_server::axum_verify_server(shutdown));
 
     Ok((handlers, handler))
 }
+
+/// 首次登录时创建交叉签名身份并签名本设备，需要密码完成服务器的二次认证
+pub async fn bootstrap_cross_signing(client: &matrix_sdk::Client, password: &str) -> Result<()> {
+    let encryption = client.encryption();
+    if encryption
+        .cross_signing_status()
+        .await
+        .map_or(false, |status| status.is_complete())
+    {
+        return Ok(());
+    }
+
+    let user_id = client.user_id().ok_or(anyhow!("not logged in"))?;
+    // 其他客户端已经建立的身份不能覆盖，只能由已验证的设备验证本设备
+    if encryption.get_user_identity(user_id).await?.is_some() {
+        log::warn!(
+            "{} already has a cross-signing identity, verify this device from another session",
+            user_id
+        );
+        return Ok(());
+    }
+
+    if let Err(e) = encryption.bootstrap_cross_signing(None).await {
+        let response = match e.uiaa_response() {
+            Some(response) => response,
+            None => return Err(e.into()),
+        };
+        let mut auth = uiaa::Password::new(
+            uiaa::UserIdentifier::UserIdOrLocalpart(user_id.as_str()),
+            password,
+        );
+        auth.session = response.session.as_deref();
+        encryption
+            .bootstrap_cross_signing(Some(uiaa::AuthData::Password(auth)))
+            .await?;
+    }
+    log::info!("bootstrapped cross-signing for {}", user_id);
+    Ok(())
+}
+
+/// 设置加密房间密钥导出文件的恢复密钥，需要在登录前调用
+///
+/// 恢复密钥不能与导出文件放在一起，只能由运维提供；未设置时不导出，并在终端输出一个新生成的密钥
+pub fn set_recovery_key(data: &Path, key: Option<String>) {
+    let legacy = data.join(LEGACY_RECOVERY_KEY_FILE);
+    if legacy.exists() {
+        log::error!(
+            "{} keeps the recovery key next to the exported room keys, \
+             move it to RECOVERY_KEY_FILE outside the data folder and delete it",
+            legacy.display()
+        );
+    }
+
+    match key {
+        Some(key) => {
+            let _ = RECOVERY_KEY.set(key);
+        }
+        None => {
+            let key = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+            log::warn!("recovery key is not set, room keys will not be exported");
+            println!(
+                "Room key export is disabled. Keep this newly generated recovery key outside \
+                 the data folder and pass it by RECOVERY_KEY or RECOVERY_KEY_FILE to enable it:\n{}",
+                key
+            );
+        }
+    }
+}
+
+fn recovery_key() -> Result<&'static str> {
+    RECOVERY_KEY
+        .get()
+        .map(String::as_str)
+        .ok_or(anyhow!("recovery key is not set"))
+}
+
+/// 导出全部房间密钥到数据目录
+pub async fn export_keys(client: &matrix_sdk::Client, data: &Path) -> Result<()> {
+    let key = recovery_key()?;
+    // 先写临时文件，导出中断时不破坏已有的备份
+    let tmp = data.join(format!("{}.tmp", KEYS_FILE));
+    client
+        .encryption()
+        .export_room_keys(tmp.clone(), key, |_| true)
+        .await?;
+    fs::rename(tmp, data.join(KEYS_FILE))?;
+    Ok(())
+}
+
+/// 新设备登录后从导出文件恢复房间密钥
+pub async fn import_keys(client: &matrix_sdk::Client, data: &Path) -> Result<()> {
+    let path = data.join(KEYS_FILE);
+    if !path.exists() {
+        return Ok(());
+    }
+    let key = recovery_key()?;
+    let result = client.encryption().import_room_keys(path, key).await?;
+    log::info!(
+        "imported {}/{} room keys from {}",
+        result.imported_count,
+        result.total_count,
+        KEYS_FILE
+    );
+    Ok(())
+}
+
+/// 启动时与之后每 6 小时导出房间密钥，`shutdown` 取消时导出最后一次后退出，未设置恢复密钥时不导出
+pub fn export_keys_periodically(
+    client: &matrix_sdk::Client,
+    data: &Path,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    let client = client.clone();
+    let data = data.to_path_buf();
+    tokio::spawn(async move {
+        if RECOVERY_KEY.get().is_none() {
+            return;
+        }
+        loop {
+            if let Err(e) = export_keys(&client, &data).await {
+                log::error!("export room keys failed: {}", e);
+            }
+            if shutdown.is_cancelled() {
+                break;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(KEYS_BACKUP_INTERVAL) => {}
+                _ = shutdown.cancelled() => {}
+            }
+        }
+    })
+}
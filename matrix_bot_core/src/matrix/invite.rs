@@ -0,0 +1,259 @@
+//! 自动处理邀请：接受白名单用户或服务器的邀请，可选拒绝其他邀请、在房间只剩 bot 时退出
+
+use std::{sync::Arc, time::Duration};
+
+use matrix_sdk::{
+    event_handler::EventHandlerHandle,
+    room::Room,
+    ruma::{
+        events::room::member::{
+            MembershipState, OriginalSyncRoomMemberEvent, StrippedRoomMemberEvent,
+        },
+        OwnedUserId, RoomId, UserId,
+    },
+    Client,
+};
+
+use super::room::registry;
+use crate::{command, config::InviteConfig};
+
+/// 接受邀请失败时的重试次数，服务器可能还没有处理完邀请
+const JOIN_RETRIES: u32 = 5;
+/// 离线期间收到、当前不允许的邀请，等待插件解析完配置中的房间后再处理
+const PENDING_GRACE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Decision {
+    Accept,
+    Reject,
+    Ignore,
+}
+
+/// 注册邀请与成员变化的处理
+pub fn attach(client: &Client, config: InviteConfig) -> Vec<EventHandlerHandle> {
+    let config = Arc::new(config);
+    let mut handlers = Vec::new();
+
+    let invite_config = config.clone();
+    handlers.push(client.add_event_handler(
+        move |ev: StrippedRoomMemberEvent, room: Room, client: Client| {
+            let config = invite_config.clone();
+            async move {
+                if Some(ev.state_key.as_ref()) != client.user_id() {
+                    return;
+                }
+                if let Room::Invited(invited) = room {
+                    on_invite(&config, Some(ev.sender), invited).await;
+                }
+            }
+        },
+    ));
+
+    if config.leave_empty {
+        handlers.push(client.add_event_handler(
+            |ev: OriginalSyncRoomMemberEvent, room: Room| async move {
+                if !matches!(
+                    ev.content.membership,
+                    MembershipState::Leave | MembershipState::Ban
+                ) {
+                    return;
+                }
+                if let Room::Joined(joined) = room {
+                    on_member_left(joined).await;
+                }
+            },
+        ));
+    }
+
+    // 离线期间收到的邀请已在登录时的首次同步中处理，不会触发上面的事件处理
+    tokio::spawn(pending_invites(client.clone(), config));
+
+    handlers
+}
+
+async fn pending_invites(client: Client, config: Arc<InviteConfig>) {
+    let mut deferred = Vec::new();
+    for invited in client.invited_rooms() {
+        let sender = inviter(&invited).await;
+        if decide_invite(&config, sender.as_deref(), invited.room_id()) == Decision::Accept {
+            on_invite(&config, sender, invited).await;
+        } else {
+            deferred.push(invited.room_id().to_owned());
+        }
+    }
+    if deferred.is_empty() {
+        return;
+    }
+
+    tokio::time::sleep(PENDING_GRACE).await;
+    for room_id in deferred {
+        // 插件可能已经接受了邀请
+        if let Some(invited) = client.get_invited_room(&room_id) {
+            let sender = inviter(&invited).await;
+            on_invite(&config, sender, invited).await;
+        }
+    }
+}
+
+async fn inviter(invited: &matrix_sdk::room::Invited) -> Option<OwnedUserId> {
+    match invited.invite_details().await {
+        Ok(details) => details.inviter.map(|member| member.user_id().to_owned()),
+        Err(e) => {
+            log::warn!("get invite of {} failed: {}", invited.room_id(), e);
+            None
+        }
+    }
+}
+
+fn decide_invite(config: &InviteConfig, sender: Option<&UserId>, room_id: &RoomId) -> Decision {
+    // 管理员的邀请与配置中使用的房间总是接受
+    let trusted = sender.map_or(false, command::is_admin) || registry::is_known(room_id);
+    decide(config, sender, trusted)
+}
+
+/// 邀请者未知时只接受 `trusted` 的邀请
+fn decide(config: &InviteConfig, sender: Option<&UserId>, trusted: bool) -> Decision {
+    let allowed = trusted
+        || sender.map_or(false, |sender| {
+            config.users.iter().any(|user| &**user == sender)
+                || config
+                    .servers
+                    .iter()
+                    .any(|server| server == sender.server_name().as_str())
+        });
+    if allowed {
+        Decision::Accept
+    } else if config.reject_others {
+        Decision::Reject
+    } else {
+        Decision::Ignore
+    }
+}
+
+/// 只剩 bot 自己且房间不在配置中使用时退出
+fn should_leave(joined_members: u64, known_room: bool) -> bool {
+    joined_members <= 1 && !known_room
+}
+
+async fn on_invite(
+    config: &InviteConfig,
+    sender: Option<OwnedUserId>,
+    invited: matrix_sdk::room::Invited,
+) {
+    let room_id = invited.room_id().to_owned();
+    let decision = decide_invite(config, sender.as_deref(), &room_id);
+    let sender = sender.map_or("unknown user".to_string(), |sender| sender.to_string());
+    match decision {
+        Decision::Accept => {}
+        Decision::Reject => {
+            log::info!("rejecting invite to {} from {}", room_id, sender);
+            if let Err(e) = invited.reject_invitation().await {
+                log::error!("reject invite to {} failed: {}", room_id, e);
+            }
+            return;
+        }
+        Decision::Ignore => {
+            log::info!("ignoring invite to {} from {}", room_id, sender);
+            return;
+        }
+    }
+
+    log::info!("accepting invite to {} from {}", room_id, sender);
+    tokio::spawn(async move {
+        let mut delay = Duration::from_secs(2);
+        for attempt in 1..=JOIN_RETRIES {
+            match invited.accept_invitation().await {
+                Ok(_) => {
+                    log::info!("joined {} invited by {}", room_id, sender);
+                    return;
+                }
+                Err(e) if attempt < JOIN_RETRIES => {
+                    log::warn!(
+                        "join {} failed: {}, retry in {}s",
+                        room_id,
+                        e,
+                        delay.as_secs()
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => log::error!("join {} failed: {}", room_id, e),
+            }
+        }
+    });
+}
+
+async fn on_member_left(joined: matrix_sdk::room::Joined) {
+    let members = joined.joined_members_count();
+    let known = registry::is_known(joined.room_id());
+    // 配置中使用的房间由配置决定，不自动退出
+    if members <= 1 && known {
+        log::info!(
+            "{} has no other members but is used in the config",
+            joined.room_id()
+        );
+    }
+    if !should_leave(members, known) {
+        return;
+    }
+    log::info!("leaving {}, no other members left", joined.room_id());
+    if let Err(e) = joined.leave().await {
+        log::error!("leave {} failed: {}", joined.room_id(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::user_id;
+
+    use super::*;
+
+    #[test]
+    fn test_decide() {
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:friends.org");
+        let eve = user_id!("@eve:evil.org");
+        let config = InviteConfig {
+            users: vec![alice.to_owned()],
+            servers: vec!["friends.org".to_string()],
+            reject_others: true,
+            ..Default::default()
+        };
+
+        assert_eq!(decide(&config, Some(alice), false), Decision::Accept);
+        assert_eq!(decide(&config, Some(bob), false), Decision::Accept);
+        assert_eq!(decide(&config, Some(eve), false), Decision::Reject);
+        // 服务器名需要完全匹配
+        let sub = user_id!("@eve:sub.friends.org");
+        assert_eq!(decide(&config, Some(sub), false), Decision::Reject);
+        // 邀请者未知时只接受可信的邀请
+        assert_eq!(decide(&config, None, false), Decision::Reject);
+        assert_eq!(decide(&config, None, true), Decision::Accept);
+        assert_eq!(decide(&config, Some(eve), true), Decision::Accept);
+    }
+
+    #[test]
+    fn test_decide_empty_allowlist() {
+        let alice = user_id!("@alice:example.org");
+
+        // 默认忽略不在白名单中的邀请
+        let config = InviteConfig::default();
+        assert_eq!(decide(&config, Some(alice), false), Decision::Ignore);
+        assert_eq!(decide(&config, Some(alice), true), Decision::Accept);
+
+        let config = InviteConfig {
+            reject_others: true,
+            ..Default::default()
+        };
+        assert_eq!(decide(&config, Some(alice), false), Decision::Reject);
+        assert_eq!(decide(&config, Some(alice), true), Decision::Accept);
+    }
+
+    #[test]
+    fn test_should_leave() {
+        assert!(should_leave(1, false));
+        assert!(should_leave(0, false));
+        assert!(!should_leave(2, false));
+        assert!(!should_leave(1, true));
+    }
+}
@@ -1,3 +1,5 @@
 pub mod client;
 pub mod e2ee;
+pub mod invite;
 pub mod room;
+mod session;
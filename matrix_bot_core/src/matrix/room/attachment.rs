@@ -0,0 +1,338 @@
+use std::{convert::TryInto, fs, io::Cursor, path::Path, sync::OnceLock};
+
+use anyhow::{anyhow, Result};
+use image::{
+    codecs::jpeg::JpegDecoder, DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat,
+};
+use matrix_sdk::{
+    attachment::{
+        AttachmentConfig, AttachmentInfo, BaseAudioInfo, BaseFileInfo, BaseImageInfo,
+        BaseThumbnailInfo, BaseVideoInfo, Thumbnail,
+    },
+    ruma::{api::client::media::get_media_config, UInt},
+    Client,
+};
+use mime_guess::{mime, Mime};
+
+use super::probe;
+
+/// 缩略图的最大宽高，超过时缩小
+const THUMBNAIL_SIZE: (u32, u32) = (800, 600);
+const THUMBNAIL_QUALITY: u8 = 80;
+/// 计算 blurhash 前缩小到的尺寸，blurhash 本身只保留很少的细节
+const BLURHASH_SIZE: u32 = 64;
+/// 超过上传限制的图片重新压缩时使用的质量
+const SHRINK_QUALITY: u8 = 85;
+const SHRINK_ATTEMPTS: u32 = 6;
+
+/// 服务器的 `m.upload.size`，查询成功后缓存
+static UPLOAD_LIMIT: OnceLock<u64> = OnceLock::new();
+
+#[derive(Debug, Clone)]
+struct ImageInfo {
+    width: u32,
+    height: u32,
+    blurhash: String,
+}
+
+#[derive(Debug, Clone)]
+struct ThumbnailInfo {
+    data: Vec<u8>,
+    mime: Mime,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Debug, Clone)]
+enum Info {
+    Image(ImageInfo),
+    Video {
+        probe: probe::Probe,
+        blurhash: Option<String>,
+    },
+    Audio(probe::Probe),
+    File,
+}
+
+/// 已读取完毕、可以重复发送的附件
+#[derive(Debug, Clone)]
+pub(crate) struct Attachment {
+    pub filename: String,
+    pub mime: Mime,
+    pub data: Vec<u8>,
+    info: Info,
+    thumbnail: Option<ThumbnailInfo>,
+}
+
+/// 服务器允许上传的最大字节数，服务器未提供或查询失败时返回 `None`
+pub(crate) async fn upload_limit(client: &Client) -> Option<u64> {
+    if let Some(limit) = UPLOAD_LIMIT.get() {
+        return Some(*limit);
+    }
+    match client
+        .send(get_media_config::v3::Request::new(), None)
+        .await
+    {
+        Ok(response) => {
+            let limit = u64::from(response.upload_size);
+            log::info!("upload size limit of the server: {} bytes", limit);
+            Some(*UPLOAD_LIMIT.get_or_init(|| limit))
+        }
+        Err(e) => {
+            log::warn!("query upload size limit failed: {}", e);
+            None
+        }
+    }
+}
+
+impl Attachment {
+    /// 读取文件并计算附件信息，视频和音频需要 ffprobe / ffmpeg，会阻塞
+    ///
+    /// matrix-sdk 0.6 只能上传内存中的数据，因此先检查大小再读取：超过 `limit` 的图片
+    /// 会缩小并重新压缩为 JPEG，其他文件直接返回错误
+    pub fn from_path(file_path: impl AsRef<Path>, limit: Option<u64>) -> Result<Self> {
+        let file_path = file_path.as_ref();
+        let size = fs::metadata(file_path)?.len();
+        let filename = file_path
+            .file_name()
+            .unwrap_or(std::ffi::OsStr::new("image.jpg"))
+            .to_str()
+            .unwrap_or("image.jpg");
+        let mime = mime_guess::from_path(file_path).first_or_octet_stream();
+
+        if let Some(limit) = limit.filter(|limit| size > *limit) {
+            if mime.type_() != mime::IMAGE {
+                return Err(anyhow!(
+                    "{} is {} bytes, larger than the upload limit of the server ({} bytes)",
+                    filename,
+                    size,
+                    limit
+                ));
+            }
+            log::info!(
+                "{} is {} bytes, compressing it below the upload limit of {} bytes",
+                filename,
+                size,
+                limit
+            );
+            let (data, image) = shrink(&image::open(file_path)?, limit)?;
+            let (info, thumbnail) = image_info(image.width(), image.height(), &image)?;
+            return Ok(Attachment {
+                filename: Path::new(filename)
+                    .with_extension("jpg")
+                    .to_string_lossy()
+                    .into_owned(),
+                mime: mime::IMAGE_JPEG,
+                data,
+                info,
+                thumbnail,
+            });
+        }
+
+        let data = fs::read(file_path)?;
+        let (info, thumbnail) = match mime.type_() {
+            mime::IMAGE => {
+                // 只读取文件头获取原始尺寸，缩略图和 blurhash 使用缩小解码的图片
+                let (width, height) = image::io::Reader::new(Cursor::new(&data))
+                    .with_guessed_format()?
+                    .into_dimensions()?;
+                image_info(width, height, &decode_scaled(&data, THUMBNAIL_SIZE)?)?
+            }
+            mime::VIDEO => {
+                let probe = probe::probe(file_path).unwrap_or_default();
+                let frame = probe::video_frame(file_path, probe.duration).and_then(|frame| {
+                    match decode_scaled(&frame, THUMBNAIL_SIZE) {
+                        Ok(frame) => Some(frame),
+                        Err(e) => {
+                            log::warn!("decode frame of {} failed: {}", filename, e);
+                            None
+                        }
+                    }
+                });
+                let (thumbnail, blurhash) = match frame {
+                    Some(frame) => (Some(thumbnail(&frame)?), Some(blurhash(&frame)?)),
+                    None => (None, None),
+                };
+                (Info::Video { probe, blurhash }, thumbnail)
+            }
+            mime::AUDIO => (
+                Info::Audio(probe::probe(file_path).unwrap_or_default()),
+                None,
+            ),
+            _ => (Info::File, None),
+        };
+
+        Ok(Attachment {
+            filename: filename.to_string(),
+            mime,
+            data,
+            info,
+            thumbnail,
+        })
+    }
+
+    pub fn config(&self) -> Result<AttachmentConfig<'_>> {
+        let size = Some(self.data.len().try_into()?);
+        let info = match &self.info {
+            Info::Image(image) => AttachmentInfo::Image(BaseImageInfo {
+                height: Some(image.height.into()),
+                width: Some(image.width.into()),
+                size,
+                blurhash: Some(image.blurhash.clone()),
+            }),
+            Info::Video { probe, blurhash } => AttachmentInfo::Video(BaseVideoInfo {
+                duration: probe.duration,
+                height: probe.height.map(UInt::from),
+                width: probe.width.map(UInt::from),
+                size,
+                blurhash: blurhash.clone(),
+            }),
+            Info::Audio(probe) => AttachmentInfo::Audio(BaseAudioInfo {
+                duration: probe.duration,
+                size,
+            }),
+            Info::File => AttachmentInfo::File(BaseFileInfo { size }),
+        };
+
+        let config = match &self.thumbnail {
+            Some(thumbnail) => AttachmentConfig::with_thumbnail(Thumbnail {
+                data: &thumbnail.data,
+                content_type: &thumbnail.mime,
+                info: Some(BaseThumbnailInfo {
+                    height: Some(thumbnail.height.into()),
+                    width: Some(thumbnail.width.into()),
+                    size: Some(thumbnail.data.len().try_into()?),
+                }),
+            }),
+            None => AttachmentConfig::new(),
+        };
+        Ok(config.info(info))
+    }
+}
+
+/// `width`、`height` 为原图尺寸，`image` 可以是缩小后的图片
+fn image_info(
+    width: u32,
+    height: u32,
+    image: &DynamicImage,
+) -> Result<(Info, Option<ThumbnailInfo>)> {
+    // 小图片直接显示原图，不需要缩略图
+    let thumbnail = if width > THUMBNAIL_SIZE.0 || height > THUMBNAIL_SIZE.1 {
+        Some(thumbnail(image)?)
+    } else {
+        None
+    };
+    let info = Info::Image(ImageInfo {
+        width,
+        height,
+        blurhash: blurhash(image)?,
+    });
+    Ok((info, thumbnail))
+}
+
+/// 解码图片，JPEG 在解码时直接缩小到不小于 `size`，避免完整解码大图
+fn decode_scaled(data: &[u8], size: (u32, u32)) -> Result<DynamicImage> {
+    if image::guess_format(data)? != ImageFormat::Jpeg {
+        return Ok(image::load_from_memory(data)?);
+    }
+    let mut decoder = JpegDecoder::new(Cursor::new(data))?;
+    decoder.scale(
+        size.0.try_into().unwrap_or(u16::MAX),
+        size.1.try_into().unwrap_or(u16::MAX),
+    )?;
+    Ok(DynamicImage::from_decoder(decoder)?)
+}
+
+fn blurhash(image: &DynamicImage) -> Result<String> {
+    let image = image.thumbnail(BLURHASH_SIZE, BLURHASH_SIZE);
+    let (width, height) = image.dimensions();
+    Ok(blurhash::encode(
+        4,
+        3,
+        width,
+        height,
+        image.to_rgba8().as_raw(),
+    )?)
+}
+
+/// 缩小到 `THUMBNAIL_SIZE` 以内并编码为 JPEG
+fn thumbnail(image: &DynamicImage) -> Result<ThumbnailInfo> {
+    let (width, height) = image.dimensions();
+    let image = if width > THUMBNAIL_SIZE.0 || height > THUMBNAIL_SIZE.1 {
+        image.thumbnail(THUMBNAIL_SIZE.0, THUMBNAIL_SIZE.1)
+    } else {
+        image.clone()
+    };
+    let data = encode_jpeg(&image, THUMBNAIL_QUALITY)?;
+    Ok(ThumbnailInfo {
+        data,
+        mime: mime::IMAGE_JPEG,
+        width: image.width(),
+        height: image.height(),
+    })
+}
+
+/// 按比例缩小并压缩为 JPEG，直到不超过 `limit` 字节，返回数据与缩小后的图片
+fn shrink(image: &DynamicImage, limit: u64) -> Result<(Vec<u8>, DynamicImage)> {
+    let mut image = image.clone();
+    for _ in 0..SHRINK_ATTEMPTS {
+        let data = encode_jpeg(&image, SHRINK_QUALITY)?;
+        if data.len() as u64 <= limit {
+            return Ok((data, image));
+        }
+        // 文件大小大致与像素数成正比，多缩小一些减少重试次数
+        let scale = (limit as f64 / data.len() as f64).sqrt() * 0.9;
+        let width = ((image.width() as f64 * scale) as u32).max(1);
+        let height = ((image.height() as f64 * scale) as u32).max(1);
+        image = image.thumbnail(width, height);
+    }
+    Err(anyhow!(
+        "can't compress the image below the upload limit of the server ({} bytes)",
+        limit
+    ))
+}
+
+fn encode_jpeg(image: &DynamicImage, quality: u8) -> Result<Vec<u8>> {
+    // JPEG 不支持透明通道
+    let image = DynamicImage::ImageRgb8(image.to_rgb8());
+    let mut data = Vec::new();
+    image.write_to(
+        &mut Cursor::new(&mut data),
+        ImageOutputFormat::Jpeg(quality),
+    )?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thumbnail() {
+        let image = DynamicImage::new_rgba8(1600, 400);
+        let thumbnail = thumbnail(&image).unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height), (800, 200));
+        assert!(image::load_from_memory(&thumbnail.data).is_ok());
+
+        let image = DynamicImage::new_rgb8(100, 50);
+        let thumbnail = thumbnail(&image).unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height), (100, 50));
+    }
+
+    #[test]
+    fn test_shrink() {
+        // 噪声图片难以压缩，需要缩小才能满足限制
+        let image = image::RgbImage::from_fn(1000, 1000, |x, y| {
+            let v = (x.wrapping_mul(7919) ^ y.wrapping_mul(104729)) as u8;
+            image::Rgb([v, v.wrapping_mul(3), v.wrapping_add(y as u8)])
+        });
+        let image = DynamicImage::ImageRgb8(image);
+        let limit = 50_000;
+        let (data, small) = shrink(&image, limit).unwrap();
+        assert!(data.len() as u64 <= limit);
+        assert!(small.width() < 1000);
+
+        let decoded = decode_scaled(&data, (100, 100)).unwrap();
+        assert!(decoded.width() >= 100 && decoded.width() <= small.width());
+    }
+}
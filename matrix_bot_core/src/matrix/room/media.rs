@@ -0,0 +1,349 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use matrix_sdk::{
+    crypto::{AttachmentDecryptor, MediaEncryptionInfo},
+    ruma::{
+        events::room::{
+            message::{MessageType, OriginalSyncRoomMessageEvent},
+            MediaSource,
+        },
+        MxcUri,
+    },
+};
+use mime_guess::Mime;
+use tokio::io::AsyncWriteExt;
+
+use super::Room;
+
+static HTTP: OnceLock<reqwest::Client> = OnceLock::new();
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// 单个文件的下载时间上限
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// 两次收到数据之间的最长间隔，避免服务器停止响应时一直等待
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 消息中的文件、图片、视频或音频
+#[derive(Debug, Clone)]
+pub struct Media {
+    /// 文件名，已去除路径部分
+    pub name: String,
+    pub mime: Mime,
+    /// 发送方声明的大小，可能不存在或不准确
+    pub size: Option<u64>,
+    source: MediaSource,
+}
+
+impl Media {
+    /// 从消息中提取媒体，其他类型的消息返回 `None`
+    pub fn from_message(msgtype: &MessageType) -> Option<Self> {
+        let (body, source, mime, size) = match msgtype {
+            MessageType::File(c) => {
+                let info = c.info.as_deref();
+                let mime = info.and_then(|i| i.mimetype.clone());
+                (&c.body, &c.source, mime, info.and_then(|i| i.size))
+            }
+            MessageType::Image(c) => {
+                let info = c.info.as_deref();
+                let mime = info.and_then(|i| i.mimetype.clone());
+                (&c.body, &c.source, mime, info.and_then(|i| i.size))
+            }
+            MessageType::Video(c) => {
+                let info = c.info.as_deref();
+                let mime = info.and_then(|i| i.mimetype.clone());
+                (&c.body, &c.source, mime, info.and_then(|i| i.size))
+            }
+            MessageType::Audio(c) => {
+                let info = c.info.as_deref();
+                let mime = info.and_then(|i| i.mimetype.clone());
+                (&c.body, &c.source, mime, info.and_then(|i| i.size))
+            }
+            _ => return None,
+        };
+
+        let name = file_name(body);
+        let mime = mime
+            .and_then(|m| m.parse().ok())
+            .unwrap_or_else(|| mime_guess::from_path(&name).first_or_octet_stream());
+        Some(Media {
+            name,
+            mime,
+            size: size.map(u64::from),
+            source: source.clone(),
+        })
+    }
+
+    pub fn from_event(event: &OriginalSyncRoomMessageEvent) -> Option<Self> {
+        Self::from_message(&event.content.msgtype)
+    }
+
+    /// 是否为加密房间中的加密文件
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self.source, MediaSource::Encrypted(_))
+    }
+
+    /// 声明的大小超过 `max_size` 时返回错误，未声明大小时只能在下载时检查
+    fn check_size(&self, max_size: u64) -> Result<()> {
+        match self.size.filter(|size| *size > max_size) {
+            Some(size) => Err(too_large(&self.name, size, max_size)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Room {
+    /// 下载媒体到 `dir`，返回文件路径，文件名加随机前缀避免冲突
+    ///
+    /// 边下载边写入文件，超过 `max_size` 字节时中止并删除文件；加密文件下载完成后解密
+    pub async fn download_to(
+        &self,
+        media: &Media,
+        dir: impl AsRef<Path>,
+        max_size: u64,
+    ) -> Result<PathBuf> {
+        // 声明的大小超出时不下载
+        media.check_size(max_size)?;
+
+        tokio::fs::create_dir_all(dir.as_ref()).await?;
+        let path = dir
+            .as_ref()
+            .join(format!("{}-{}", uuid::Uuid::new_v4().simple(), media.name));
+        let client = self.0.client();
+        let result = match &media.source {
+            MediaSource::Plain(uri) => fetch(&client, uri, &path, &media.name, max_size).await,
+            MediaSource::Encrypted(file) => {
+                // AES-CTR 密文与明文长度相同，下载时同样按 `max_size` 限制
+                let part = path.with_file_name(format!(
+                    "{}.part",
+                    path.file_name().unwrap_or_default().to_string_lossy()
+                ));
+                let result = async {
+                    fetch(&client, &file.url, &part, &media.name, max_size).await?;
+                    let info = MediaEncryptionInfo::from((**file).clone());
+                    let (part, path) = (part.clone(), path.clone());
+                    tokio::task::spawn_blocking(move || decrypt(&part, &path, info)).await?
+                }
+                .await;
+                let _ = tokio::fs::remove_file(&part).await;
+                result
+            }
+        };
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(e);
+        }
+        Ok(path)
+    }
+}
+
+/// 下载媒体使用的 HTTP 客户端，与 matrix-sdk 一样读取系统代理设置
+fn http() -> Result<&'static reqwest::Client> {
+    if let Some(client) = HTTP.get() {
+        return Ok(client);
+    }
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("matrix_bot/", env!("CARGO_PKG_VERSION")))
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()?;
+    Ok(HTTP.get_or_init(|| client))
+}
+
+fn too_large(name: &str, size: u64, max_size: u64) -> anyhow::Error {
+    anyhow!("{} is too large: {} > {} bytes", name, size, max_size)
+}
+
+/// 从媒体仓库流式下载到 `path`，超过 `max_size` 字节时中止
+async fn fetch(
+    client: &matrix_sdk::Client,
+    uri: &MxcUri,
+    path: &Path,
+    name: &str,
+    max_size: u64,
+) -> Result<()> {
+    let (server_name, media_id) = uri.parts()?;
+    let mut url = client.homeserver().await;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid homeserver url"))?
+        .pop_if_empty()
+        .extend([
+            "_matrix",
+            "media",
+            "v3",
+            "download",
+            server_name.as_str(),
+            media_id,
+        ]);
+
+    let mut request = http()?.get(url).timeout(DOWNLOAD_TIMEOUT);
+    if let Some(token) = client.access_token() {
+        request = request.bearer_auth(token);
+    }
+    let mut response = request.send().await?.error_for_status()?;
+    if let Some(size) = response.content_length().filter(|size| *size > max_size) {
+        return Err(too_large(name, size, max_size));
+    }
+
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut total = 0;
+    loop {
+        let chunk = tokio::time::timeout(READ_TIMEOUT, response.chunk())
+            .await
+            .map_err(|_| anyhow!("download {} timed out", name))??;
+        let Some(chunk) = chunk else {
+            break;
+        };
+        total += chunk.len() as u64;
+        if total > max_size {
+            return Err(too_large(name, total, max_size));
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// 解密下载的密文，哈希不匹配时返回错误
+fn decrypt(encrypted: &Path, path: &Path, info: MediaEncryptionInfo) -> Result<()> {
+    let mut input = fs::File::open(encrypted)?;
+    let mut decryptor = AttachmentDecryptor::new(&mut input, info)?;
+    let mut output = fs::File::create(path)?;
+    std::io::copy(&mut decryptor, &mut output)?;
+    Ok(())
+}
+
+/// 去除路径部分，避免写到目标目录之外
+fn file_name(body: &str) -> String {
+    Path::new(body.trim())
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("file")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use matrix_sdk::{
+        crypto::AttachmentEncryptor,
+        ruma::{
+            events::room::{
+                message::{
+                    FileInfo, FileMessageEventContent, ImageMessageEventContent,
+                    VideoMessageEventContent,
+                },
+                ImageInfo,
+            },
+            mxc_uri,
+            serde::Base64,
+            uint,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_from_message() {
+        let url = mxc_uri!("mxc://example.org/media");
+
+        let mut info = FileInfo::new();
+        info.mimetype = Some("application/x-bittorrent".to_string());
+        info.size = Some(uint!(1024));
+        let file = MessageType::File(FileMessageEventContent::plain(
+            "dir/a.torrent".to_string(),
+            url.to_owned(),
+            Some(Box::new(info)),
+        ));
+        let media = Media::from_message(&file).unwrap();
+        assert_eq!(media.name, "a.torrent");
+        assert_eq!(media.mime.essence_str(), "application/x-bittorrent");
+        assert_eq!(media.size, Some(1024));
+        assert!(!media.is_encrypted());
+
+        // 没有 info 时按文件名推断类型
+        let image = MessageType::Image(ImageMessageEventContent::plain(
+            "a.png".to_string(),
+            url.to_owned(),
+            None,
+        ));
+        let media = Media::from_message(&image).unwrap();
+        assert_eq!(media.mime, mime_guess::mime::IMAGE_PNG);
+        assert_eq!(media.size, None);
+
+        let mut info = ImageInfo::new();
+        info.size = Some(uint!(10));
+        let image = MessageType::Image(ImageMessageEventContent::plain(
+            "a.png".to_string(),
+            url.to_owned(),
+            Some(Box::new(info)),
+        ));
+        assert_eq!(Media::from_message(&image).unwrap().size, Some(10));
+
+        let video = MessageType::Video(VideoMessageEventContent::plain(
+            "a.mp4".to_string(),
+            url.to_owned(),
+            None,
+        ));
+        let media = Media::from_message(&video).unwrap();
+        assert_eq!(media.mime.essence_str(), "video/mp4");
+
+        assert!(Media::from_message(&MessageType::text_plain("a.torrent")).is_none());
+    }
+
+    #[test]
+    fn test_check_size() {
+        let file = MessageType::File(FileMessageEventContent::plain(
+            "a.torrent".to_string(),
+            mxc_uri!("mxc://example.org/media").to_owned(),
+            None,
+        ));
+        let mut media = Media::from_message(&file).unwrap();
+        assert!(media.check_size(100).is_ok());
+
+        media.size = Some(100);
+        assert!(media.check_size(100).is_ok());
+        media.size = Some(101);
+        assert!(media.check_size(100).is_err());
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let data = b"matrix bot attachment".repeat(100);
+        let mut cursor = Cursor::new(data.clone());
+        let mut encryptor = AttachmentEncryptor::new(&mut cursor);
+        let mut encrypted = Vec::new();
+        encryptor.read_to_end(&mut encrypted).unwrap();
+        let info = encryptor.finish();
+
+        let id = uuid::Uuid::new_v4().simple();
+        let part = std::env::temp_dir().join(format!("matrix_bot_test_media-{}.part", id));
+        let path = std::env::temp_dir().join(format!("matrix_bot_test_media-{}", id));
+        fs::write(&part, &encrypted).unwrap();
+
+        decrypt(&part, &path, info.clone()).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        let mut tampered = info;
+        tampered
+            .hashes
+            .insert("sha256".to_string(), Base64::new(vec![0; 32]));
+        assert!(decrypt(&part, &path, tampered).is_err());
+
+        fs::remove_file(&part).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name("a.torrent"), "a.torrent");
+        assert_eq!(file_name("../../etc/passwd"), "passwd");
+        assert_eq!(file_name(".."), "file");
+        assert_eq!(file_name(""), "file");
+    }
+}
@@ -0,0 +1,267 @@
+use anyhow::{anyhow, Result};
+use matrix_sdk::ruma::events::{
+    reaction::{self, ReactionEventContent},
+    room::message::{MessageType, Relation, Replacement, RoomMessageEvent},
+    MessageLikeEventContent,
+};
+use matrix_sdk::ruma::{EventId, OwnedEventId, TransactionId};
+use matrix_sdk::Client;
+use matrix_sdk::{room::Joined, ruma::events::room::message::RoomMessageEventContent};
+
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use tokio::task::JoinSet;
+
+use self::attachment::Attachment;
+use crate::template::Message;
+
+mod attachment;
+mod media;
+mod probe;
+mod queue;
+pub mod registry;
+
+pub use media::Media;
+
+#[derive(Debug, Clone)]
+pub struct Room(pub Joined);
+
+impl Room {
+    /// `room_id` 可以是房间 ID、`#alias:server` 或 `@user:server`（私聊）
+    pub async fn new(client: &Client, room_id: &str) -> Result<Self> {
+        if !client.logged_in() {
+            return Err(anyhow!("Not logged in"));
+        }
+        Ok(Room(registry::get(client, room_id).await?))
+    }
+
+    /// 并发解析多个房间，失败的条目会汇总输出，只有全部失败时才返回错误
+    pub async fn resolve_all<I, S>(client: &Client, rooms: I) -> Result<HashMap<String, Room>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut set = JoinSet::new();
+        for room in rooms {
+            let client = client.clone();
+            let room = room.as_ref().to_string();
+            set.spawn(async move {
+                let result = Room::new(&client, &room).await;
+                (room, result)
+            });
+        }
+
+        let total = set.len();
+        let mut resolved = HashMap::new();
+        let mut failed = Vec::new();
+        while let Some(result) = set.join_next().await {
+            match result? {
+                (room, Ok(joined)) => {
+                    resolved.insert(room, joined);
+                }
+                (room, Err(e)) => failed.push((room, e)),
+            }
+        }
+
+        if !failed.is_empty() {
+            let mut msg = format!("{}/{} rooms failed to resolve:", failed.len(), total);
+            for (room, e) in &failed {
+                msg.push_str(&format!("\n  {:?}: {}", room, e));
+            }
+            log::error!("{}", msg);
+        }
+        if resolved.is_empty() && total > 0 {
+            return Err(anyhow!("None of the configured rooms could be resolved"));
+        }
+
+        Ok(resolved)
+    }
+
+    /// 通过房间队列发送消息，被限流或网络错误时自动重试，返回最终的事件 ID
+    pub async fn send<C>(&self, content: C) -> Result<OwnedEventId>
+    where
+        C: MessageLikeEventContent + Clone + Send + Sync + 'static,
+    {
+        // 重试时使用同一个 txn_id，避免服务器重复发送
+        let txn_id = TransactionId::new();
+        queue::send(&self.0, move |room| {
+            let content = content.clone();
+            let txn_id = txn_id.clone();
+            async move { Ok(room.send(content, Some(&*txn_id)).await?.event_id) }
+        })
+        .await
+    }
+
+    /// 发送文件，超过服务器上传限制的图片会被压缩，其他文件返回错误
+    pub async fn send_attachment(&self, file_path: impl AsRef<Path>) -> Result<OwnedEventId> {
+        let file_path = file_path.as_ref().to_path_buf();
+        let limit = attachment::upload_limit(&self.0.client()).await;
+        let attachment = Arc::new(
+            tokio::task::spawn_blocking(move || Attachment::from_path(file_path, limit)).await??,
+        );
+        let txn_id = TransactionId::new();
+        queue::send(&self.0, move |room| {
+            let attachment = attachment.clone();
+            let txn_id = txn_id.clone();
+            async move {
+                let config = attachment.config()?.txn_id(&txn_id);
+                let response = room
+                    .send_attachment(
+                        &attachment.filename,
+                        &attachment.mime,
+                        &attachment.data,
+                        config,
+                    )
+                    .await?;
+                Ok(response.event_id)
+            }
+        })
+        .await
+    }
+
+    pub async fn send_msg(&self, msg: &str, is_markdown: bool) -> Result<OwnedEventId> {
+        self.send(text_content(msg, is_markdown)).await
+    }
+
+    /// 发送模板渲染的消息，没有 HTML 正文时作为纯文本发送
+    pub async fn send_message(&self, msg: &Message) -> Result<OwnedEventId> {
+        match &msg.html {
+            Some(html) => self.send_html(&msg.plain, html).await,
+            None => self.send_msg(&msg.plain, false).await,
+        }
+    }
+
+    pub async fn send_relates_message(
+        &self,
+        msg: &Message,
+        event_id: &str,
+    ) -> Result<OwnedEventId> {
+        match &msg.html {
+            Some(html) => self.send_relates_html(&msg.plain, html, event_id).await,
+            None => self.send_relates_msg(&msg.plain, event_id, false).await,
+        }
+    }
+
+    pub async fn send_html(&self, msg: &str, html_msg: &str) -> Result<OwnedEventId> {
+        let msg = RoomMessageEventContent::text_html(msg, html_msg);
+
+        self.send(msg).await
+    }
+
+    pub async fn send_relates_html(
+        &self,
+        msg: &str,
+        html_msg: &str,
+        event_id: &str,
+    ) -> Result<OwnedEventId> {
+        let msg = RoomMessageEventContent::text_html(msg, html_msg);
+
+        let msg = self.make_reply(msg, event_id).await?;
+        self.send(msg).await
+    }
+
+    pub async fn send_relates_msg(
+        &self,
+        msg: &str,
+        event_id: &str,
+        is_markdown: bool,
+    ) -> Result<OwnedEventId> {
+        let msg = self
+            .make_reply(text_content(msg, is_markdown), event_id)
+            .await?;
+        self.send(msg).await
+    }
+
+    /// 编辑已发送的消息（m.replace），返回编辑事件的 ID
+    pub async fn edit_msg(
+        &self,
+        event_id: &EventId,
+        msg: &str,
+        is_markdown: bool,
+    ) -> Result<OwnedEventId> {
+        self.edit(event_id, text_content(msg, is_markdown)).await
+    }
+
+    pub async fn edit_html(
+        &self,
+        event_id: &EventId,
+        msg: &str,
+        html_msg: &str,
+    ) -> Result<OwnedEventId> {
+        self.edit(event_id, RoomMessageEventContent::text_html(msg, html_msg))
+            .await
+    }
+
+    async fn edit(
+        &self,
+        event_id: &EventId,
+        new_content: RoomMessageEventContent,
+    ) -> Result<OwnedEventId> {
+        // 不支持编辑的客户端显示带 * 前缀的回退内容
+        let mut content = new_content.clone();
+        if let MessageType::Text(text) = &mut content.msgtype {
+            text.body = format!("* {}", text.body);
+            if let Some(formatted) = &mut text.formatted {
+                formatted.body = format!("* {}", formatted.body);
+            }
+        }
+        content.relates_to = Some(Relation::Replacement(Replacement::new(
+            event_id.to_owned(),
+            Box::new(new_content),
+        )));
+
+        self.send(content).await
+    }
+
+    /// 撤回事件，返回撤回事件的 ID
+    pub async fn redact(&self, event_id: &EventId, reason: Option<&str>) -> Result<OwnedEventId> {
+        let event_id = event_id.to_owned();
+        let reason = reason.map(|r| r.to_string());
+        let txn_id = TransactionId::new();
+        queue::send(&self.0, move |room| {
+            let event_id = event_id.clone();
+            let reason = reason.clone();
+            let txn_id = txn_id.clone();
+            async move {
+                let response = room
+                    .redact(&event_id, reason.as_deref(), Some(txn_id))
+                    .await
+                    .map_err(matrix_sdk::Error::from)?;
+                Ok(response.event_id)
+            }
+        })
+        .await
+    }
+
+    /// 对事件添加回应，`key` 通常为 emoji
+    pub async fn react(&self, event_id: &EventId, key: &str) -> Result<OwnedEventId> {
+        let content = ReactionEventContent::new(reaction::Relation::new(
+            event_id.to_owned(),
+            key.to_string(),
+        ));
+
+        self.send(content).await
+    }
+
+    async fn make_reply(
+        &self,
+        msg: RoomMessageEventContent,
+        event_id: &str,
+    ) -> Result<RoomMessageEventContent> {
+        let event_id = OwnedEventId::try_from(event_id)?;
+        let timeline_event = self.0.event(&event_id).await?;
+        let event_content = timeline_event.event.deserialize_as::<RoomMessageEvent>()?;
+        let original_message = event_content
+            .as_original()
+            .ok_or(anyhow!("Can't reply to redacted event {}", event_id))?;
+        Ok(msg.make_reply_to(original_message))
+    }
+}
+
+fn text_content(msg: &str, is_markdown: bool) -> RoomMessageEventContent {
+    if is_markdown {
+        RoomMessageEventContent::text_markdown(msg)
+    } else {
+        RoomMessageEventContent::text_plain(msg)
+    }
+}
@@ -0,0 +1,223 @@
+//! 通过 ffprobe / ffmpeg 获取视频和音频的时长、尺寸与预览帧，命令不存在或失败时返回 `None`
+
+use std::{
+    io::{self, Read},
+    path::Path,
+    process::{self, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+/// 截取预览帧的位置，视频较短时取中间
+const FRAME_AT: Duration = Duration::from_secs(1);
+/// 损坏或流式的输入可能让命令一直运行，超时后结束进程
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(super) struct Probe {
+    pub duration: Option<Duration>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct Output {
+    #[serde(default)]
+    streams: Vec<Stream>,
+    format: Option<Format>,
+}
+
+#[derive(Deserialize)]
+struct Stream {
+    codec_type: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    duration: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Format {
+    duration: Option<String>,
+}
+
+pub(super) fn probe(path: &Path) -> Option<Probe> {
+    let mut command = Command::new("ffprobe");
+    command
+        .args([
+            "-v",
+            "error",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+        ])
+        .arg(path);
+    match run(command, TIMEOUT) {
+        Ok(output) if output.status.success() => match parse(&output.stdout) {
+            Ok(probe) => Some(probe),
+            Err(e) => {
+                log::warn!("parse ffprobe output of {} failed: {}", path.display(), e);
+                None
+            }
+        },
+        Ok(output) => {
+            log::warn!(
+                "ffprobe {} failed: {}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            log::warn!("run ffprobe failed, media info is skipped: {}", e);
+            None
+        }
+    }
+}
+
+/// 截取一帧，返回 PNG 数据
+pub(super) fn video_frame(path: &Path, duration: Option<Duration>) -> Option<Vec<u8>> {
+    let at = match duration {
+        Some(duration) if duration < FRAME_AT * 2 => duration / 2,
+        _ => FRAME_AT,
+    };
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-v", "error", "-ss"])
+        .arg(format!("{:.3}", at.as_secs_f64()))
+        .arg("-i")
+        .arg(path)
+        .args(["-frames:v", "1", "-f", "image2pipe", "-c:v", "png", "-"]);
+    match run(command, TIMEOUT) {
+        Ok(output) if output.status.success() && !output.stdout.is_empty() => Some(output.stdout),
+        Ok(output) => {
+            log::warn!(
+                "extract frame of {} failed: {}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            log::warn!("run ffmpeg failed, thumbnail is skipped: {}", e);
+            None
+        }
+    }
+}
+
+/// 与 `Command::output` 相同，超过 `timeout` 时结束进程并返回错误
+fn run(mut command: Command, timeout: Duration) -> io::Result<process::Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // 在其他线程读取输出，避免管道写满后子进程阻塞
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stdout = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).map(|_| buf)
+    });
+    let stderr = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).map(|_| buf)
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("killed after {}s", timeout.as_secs()),
+            ));
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let join = |handle: thread::JoinHandle<io::Result<Vec<u8>>>| {
+        handle
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "reader panicked")))
+    };
+    Ok(process::Output {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
+
+fn parse(json: &[u8]) -> Result<Probe> {
+    let output: Output = serde_json::from_slice(json)?;
+    let video = output
+        .streams
+        .iter()
+        .find(|s| s.codec_type.as_deref() == Some("video"));
+
+    // 容器的时长更准确，没有时使用流的时长
+    let duration = output
+        .format
+        .and_then(|f| f.duration)
+        .or_else(|| output.streams.iter().find_map(|s| s.duration.clone()))
+        .and_then(|d| d.parse::<f64>().ok())
+        .filter(|d| d.is_finite() && *d >= 0.0)
+        .map(Duration::from_secs_f64);
+
+    Ok(Probe {
+        duration,
+        width: video.and_then(|v| v.width),
+        height: video.and_then(|v| v.height),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_timeout() {
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        let started = Instant::now();
+        let err = run(command, Duration::from_millis(200)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let mut command = Command::new("echo");
+        command.arg("ok");
+        let output = run(command, Duration::from_secs(5)).unwrap();
+        assert_eq!(output.stdout, b"ok\n");
+    }
+
+    #[test]
+    fn test_parse() {
+        let json = br#"{
+            "streams": [
+                {"codec_type": "audio", "duration": "9.98"},
+                {"codec_type": "video", "width": 1920, "height": 1080, "duration": "10.0"}
+            ],
+            "format": {"duration": "10.500000"}
+        }"#;
+        assert_eq!(
+            parse(json).unwrap(),
+            Probe {
+                duration: Some(Duration::from_millis(10500)),
+                width: Some(1920),
+                height: Some(1080),
+            }
+        );
+
+        let json = br#"{"streams": [{"codec_type": "audio", "duration": "N/A"}]}"#;
+        assert_eq!(parse(json).unwrap(), Probe::default());
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use matrix_sdk::{
+    room::Joined,
+    ruma::{api::client::error::ErrorKind, OwnedEventId, OwnedRoomId, RoomId},
+    HttpError,
+};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::metrics;
+
+const MAX_RETRIES: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// 房间队列空闲超过该时间后结束 worker，下次发送时重新创建
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+type Job =
+    Box<dyn FnMut(Joined) -> Pin<Box<dyn Future<Output = Result<OwnedEventId>> + Send>> + Send>;
+
+struct Task {
+    job: Job,
+    tx: oneshot::Sender<Result<OwnedEventId>>,
+}
+
+static QUEUES: OnceLock<Mutex<HashMap<OwnedRoomId, mpsc::UnboundedSender<Task>>>> = OnceLock::new();
+
+/// 将发送任务放入房间队列，同一房间的任务按顺序执行，失败时按需重试
+pub(crate) async fn send<F, Fut>(room: &Joined, mut job: F) -> Result<OwnedEventId>
+where
+    F: FnMut(Joined) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<OwnedEventId>> + Send + 'static,
+{
+    let plugin = crate::plugin::current().unwrap_or("core");
+    let (tx, rx) = oneshot::channel();
+    let task = Task {
+        job: Box::new(move |room| Box::pin(job(room))),
+        tx,
+    };
+    enqueue(room, task)?;
+
+    let result = rx
+        .await
+        .map_err(|_| anyhow!("send queue of {} dropped task", room.room_id()))?;
+    let counter = match result {
+        Ok(_) => "matrix_bot_messages_sent_total",
+        Err(_) => "matrix_bot_messages_failed_total",
+    };
+    metrics::inc(counter, &[("plugin", plugin)]);
+    result
+}
+
+fn queues() -> &'static Mutex<HashMap<OwnedRoomId, mpsc::UnboundedSender<Task>>> {
+    QUEUES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// 在锁内放入任务，worker 空闲退出时同样在锁内确认队列为空，避免任务放入已退出的队列
+fn enqueue(room: &Joined, task: Task) -> Result<()> {
+    let mut queues = queues().lock().unwrap();
+    if queues.get(room.room_id()).map_or(true, |tx| tx.is_closed()) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(worker(room.clone(), rx));
+        queues.insert(room.room_id().to_owned(), tx);
+    }
+    queues[room.room_id()]
+        .send(task)
+        .map_err(|_| anyhow!("send queue of {} closed", room.room_id()))
+}
+
+async fn worker(room: Joined, mut rx: mpsc::UnboundedReceiver<Task>) {
+    loop {
+        let mut task = match tokio::time::timeout(IDLE_TIMEOUT, rx.recv()).await {
+            Ok(Some(task)) => task,
+            Ok(None) => return,
+            Err(_) => {
+                let mut queues = queues().lock().unwrap();
+                match rx.try_recv() {
+                    Ok(task) => task,
+                    Err(_) => {
+                        queues.remove(room.room_id());
+                        return;
+                    }
+                }
+            }
+        };
+        let result = run_with_retry(room.room_id(), || (task.job)(room.clone())).await;
+        let _ = task.tx.send(result);
+    }
+}
+
+async fn run_with_retry<F, Fut>(room_id: &RoomId, mut job: F) -> Result<OwnedEventId>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<OwnedEventId>>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 0;
+
+    loop {
+        let err = match job().await {
+            Ok(event_id) => return Ok(event_id),
+            Err(e) => e,
+        };
+
+        attempt += 1;
+        let delay = match retry_delay(&err, backoff) {
+            Some(delay) if attempt <= MAX_RETRIES => delay,
+            _ => return Err(err),
+        };
+
+        log::warn!(
+            "send to {} failed: {}, retry {}/{} in {:?}",
+            room_id,
+            err,
+            attempt,
+            MAX_RETRIES,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+        backoff = next_backoff(backoff);
+    }
+}
+
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(MAX_BACKOFF)
+}
+
+/// 被限流时使用服务器给出的等待时间，网络错误按指数退避，其余错误不重试
+fn retry_delay(err: &anyhow::Error, backoff: Duration) -> Option<Duration> {
+    let err = err.downcast_ref::<matrix_sdk::Error>()?;
+
+    if let Some(ErrorKind::LimitExceeded { retry_after_ms }) = err.client_api_error_kind() {
+        return Some(retry_after_ms.unwrap_or(backoff));
+    }
+
+    match err {
+        matrix_sdk::Error::Http(HttpError::Reqwest(_)) => Some(backoff),
+        matrix_sdk::Error::Http(HttpError::Server(status)) if status.is_server_error() => {
+            Some(backoff)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::{
+        ruma::api::{
+            client::Error as ClientApiError,
+            error::{FromHttpResponseError, ServerError},
+        },
+        RumaApiError,
+    };
+    use reqwest::StatusCode;
+
+    use super::*;
+
+    fn limit_exceeded(retry_after_ms: Option<Duration>) -> anyhow::Error {
+        let error = ClientApiError {
+            kind: ErrorKind::LimitExceeded { retry_after_ms },
+            message: "too many requests".to_string(),
+            status_code: StatusCode::TOO_MANY_REQUESTS,
+        };
+        matrix_sdk::Error::Http(HttpError::Api(FromHttpResponseError::Server(
+            ServerError::Known(RumaApiError::ClientApi(error)),
+        )))
+        .into()
+    }
+
+    fn server_error(status: StatusCode) -> anyhow::Error {
+        matrix_sdk::Error::Http(HttpError::Server(status)).into()
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let backoff = Duration::from_secs(4);
+        assert_eq!(
+            retry_delay(&limit_exceeded(Some(Duration::from_millis(1500))), backoff),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(retry_delay(&limit_exceeded(None), backoff), Some(backoff));
+
+        // 无效的 URL 在构建请求时就会产生 reqwest 错误
+        let err = reqwest::Client::new()
+            .get("http://[::1")
+            .build()
+            .unwrap_err();
+        let err: anyhow::Error = matrix_sdk::Error::Http(HttpError::Reqwest(err)).into();
+        assert_eq!(retry_delay(&err, backoff), Some(backoff));
+
+        assert_eq!(
+            retry_delay(&server_error(StatusCode::BAD_GATEWAY), backoff),
+            Some(backoff)
+        );
+        assert_eq!(
+            retry_delay(&server_error(StatusCode::NOT_FOUND), backoff),
+            None
+        );
+        assert_eq!(retry_delay(&anyhow!("not a matrix error"), backoff), None);
+    }
+
+    #[test]
+    fn test_next_backoff() {
+        assert_eq!(next_backoff(INITIAL_BACKOFF), Duration::from_secs(2));
+        assert_eq!(next_backoff(Duration::from_secs(40)), MAX_BACKOFF);
+        assert_eq!(next_backoff(MAX_BACKOFF), MAX_BACKOFF);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_with_retry() {
+        let room_id = matrix_sdk::ruma::room_id!("!queue:example.org");
+        let start = tokio::time::Instant::now();
+        let mut attempts = 0;
+        let result = run_with_retry(room_id, || {
+            attempts += 1;
+            async { Err(server_error(StatusCode::BAD_GATEWAY)) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, MAX_RETRIES + 1);
+
+        let mut expected = Duration::ZERO;
+        let mut backoff = INITIAL_BACKOFF;
+        for _ in 0..MAX_RETRIES {
+            expected += backoff;
+            backoff = next_backoff(backoff);
+        }
+        assert_eq!(start.elapsed(), expected);
+
+        // 不可重试的错误直接返回
+        let mut attempts = 0;
+        let result = run_with_retry(room_id, || {
+            attempts += 1;
+            async { Err(server_error(StatusCode::FORBIDDEN)) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
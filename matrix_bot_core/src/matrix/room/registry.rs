@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    sync::{OnceLock, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use matrix_sdk::{
+    room::Joined,
+    ruma::{
+        api::client::room::{create_room, Visibility},
+        events::direct::DirectEventContent,
+        OwnedRoomId, OwnedUserId, RoomAliasId, RoomId, UserId,
+    },
+    Client,
+};
+
+const INVITE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// 配置中的房间（ID、别名或用户）到房间 ID 的缓存
+static RESOLVED: OnceLock<RwLock<HashMap<String, OwnedRoomId>>> = OnceLock::new();
+
+fn resolved() -> &'static RwLock<HashMap<String, OwnedRoomId>> {
+    RESOLVED.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// 将 `!id:server`、`#alias:server` 或 `@user:server` 解析为房间 ID，结果会被缓存
+///
+/// 用户 ID 会解析为与该用户的私聊，不存在时自动创建
+pub async fn resolve(client: &Client, room: &str) -> Result<OwnedRoomId> {
+    let room = room.trim();
+    if let Some(room_id) = resolved().read().unwrap().get(room) {
+        return Ok(room_id.clone());
+    }
+
+    let room_id = if room.starts_with('#') {
+        let alias = RoomAliasId::parse(room).map_err(|e| anyhow!("Invalid alias: {}", e))?;
+        client
+            .resolve_room_alias(&alias)
+            .await
+            .map_err(|e| anyhow!("Can't resolve alias: {}", e))?
+            .room_id
+    } else if room.starts_with('@') {
+        let user_id = UserId::parse(room).map_err(|e| anyhow!("Invalid user id: {}", e))?;
+        dm_room(client, &user_id).await?
+    } else {
+        RoomId::parse(room).map_err(|e| anyhow!("Invalid room id: {}", e))?
+    };
+
+    resolved()
+        .write()
+        .unwrap()
+        .insert(room.to_string(), room_id.clone());
+    Ok(room_id)
+}
+
+/// 房间是否在配置中使用过（已被解析）
+pub fn is_known(room_id: &RoomId) -> bool {
+    resolved()
+        .read()
+        .unwrap()
+        .values()
+        .any(|resolved| resolved == room_id)
+}
+
+/// 获取已加入的房间，必要时接受邀请或尝试加入，并等待 sync 中的邀请到达
+pub async fn get(client: &Client, room: &str) -> Result<Joined> {
+    let room_id = resolve(client, room).await?;
+
+    if let Some(joined) = client.get_joined_room(&room_id) {
+        return Ok(joined);
+    }
+
+    if client.get_invited_room(&room_id).is_none() {
+        // 公开房间可以直接加入，否则等待邀请
+        match client.join_room_by_id(&room_id).await {
+            Ok(_) => return wait_joined(client, &room_id).await,
+            Err(e) => log::info!("Can't join {} directly ({}), waiting for invite", room, e),
+        }
+    }
+
+    let timeout = Instant::now();
+    while timeout.elapsed() < INVITE_TIMEOUT {
+        if let Some(joined) = client.get_joined_room(&room_id) {
+            return Ok(joined);
+        }
+        if let Some(invited) = client.get_invited_room(&room_id) {
+            invited
+                .accept_invitation()
+                .await
+                .map_err(|e| anyhow!("Can't accept invitation:{e}"))?;
+            return wait_joined(client, &room_id).await;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    Err(anyhow!("Can't find room {}", room))
+}
+
+async fn dm_room(client: &Client, user_id: &UserId) -> Result<OwnedRoomId> {
+    if let Some(room) = client
+        .joined_rooms()
+        .into_iter()
+        .find(|room| room.is_direct() && room.direct_targets().contains(user_id))
+    {
+        return Ok(room.room_id().to_owned());
+    }
+
+    log::info!("No direct room with {}, creating one", user_id);
+    let invite: [OwnedUserId; 1] = [user_id.to_owned()];
+    let mut request = create_room::v3::Request::new();
+    request.invite = &invite;
+    request.is_direct = true;
+    request.visibility = Visibility::Private;
+    request.preset = Some(create_room::v3::RoomPreset::TrustedPrivateChat);
+
+    let response = client
+        .create_room(request)
+        .await
+        .map_err(|e| anyhow!("Can't create direct room: {}", e))?;
+    // 房间本身不会出现在 m.direct 中，不记录的话重启后会再创建一个私聊
+    if let Err(e) = mark_direct(client, user_id, &response.room_id).await {
+        log::warn!("Can't mark {} as direct room: {}", response.room_id, e);
+    }
+    Ok(response.room_id)
+}
+
+/// 将房间加入账号数据 `m.direct` 中该用户的私聊列表
+async fn mark_direct(client: &Client, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+    let account = client.account();
+    let mut content = match account.account_data::<DirectEventContent>().await? {
+        Some(raw) => raw.deserialize()?,
+        None => DirectEventContent::default(),
+    };
+    let rooms = content.entry(user_id.to_owned()).or_default();
+    if !rooms.iter().any(|r| r == room_id) {
+        rooms.push(room_id.to_owned());
+    }
+    account.set_account_data(content).await?;
+    Ok(())
+}
+
+async fn wait_joined(client: &Client, room_id: &RoomId) -> Result<Joined> {
+    let timeout = Instant::now();
+    while timeout.elapsed() < INVITE_TIMEOUT {
+        if let Some(joined) = client.get_joined_room(room_id) {
+            return Ok(joined);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    Err(anyhow!("Joined {} but it never showed up in sync", room_id))
+}
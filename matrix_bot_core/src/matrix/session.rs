@@ -0,0 +1,115 @@
+//! `session.json` 的读写，设置了数据库口令时使用同一口令加密保存
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use matrix_sdk::Session;
+use matrix_sdk_store_encryption::StoreCipher;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+struct Encrypted {
+    /// 用口令加密导出的密钥
+    cipher: Vec<u8>,
+    session: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Stored {
+    Encrypted(Encrypted),
+    Plain(Session),
+}
+
+pub(super) enum Loaded {
+    Session(Session),
+    /// 设置了口令但已有的数据库未加密，需要先用旧会话导出密钥再重新登录
+    Unencrypted(Session),
+    /// 需要重新登录的原因
+    Relogin(String),
+}
+
+pub(super) fn load(path: &Path, passphrase: Option<&str>) -> Result<Loaded> {
+    if !path.exists() {
+        return Ok(Loaded::Relogin(format!("{} not found", path.display())));
+    }
+    let text = fs::read_to_string(path)?;
+    let stored = match serde_json::from_str(&text) {
+        Ok(stored) => stored,
+        Err(e) => {
+            return Ok(Loaded::Relogin(format!(
+                "{} is invalid: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+
+    match (stored, passphrase) {
+        (Stored::Plain(session), None) => Ok(Loaded::Session(session)),
+        // 未加密的数据库无法原地加密，只能重建
+        (Stored::Plain(session), Some(_)) => Ok(Loaded::Unencrypted(session)),
+        (Stored::Encrypted(_), None) => bail!(
+            "{} is encrypted, set STORE_PASSPHRASE or STORE_PASSPHRASE_FILE",
+            path.display()
+        ),
+        (Stored::Encrypted(encrypted), Some(passphrase)) => {
+            let cipher = StoreCipher::import(passphrase, &encrypted.cipher)
+                .map_err(|_| anyhow!("can't decrypt {}: wrong passphrase", path.display()))?;
+            let session = cipher.decrypt_value(&encrypted.session)?;
+            Ok(Loaded::Session(session))
+        }
+    }
+}
+
+pub(super) fn save(path: &Path, session: &Session, passphrase: Option<&str>) -> Result<()> {
+    let text = match passphrase {
+        Some(passphrase) => {
+            let cipher = StoreCipher::new()?;
+            serde_json::to_string(&Encrypted {
+                cipher: cipher.export(passphrase)?,
+                session: cipher.encrypt_value(session)?,
+            })?
+        }
+        None => serde_json::to_string(session)?,
+    };
+    fs::write(path, text)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{device_id, user_id};
+
+    use super::*;
+
+    #[test]
+    fn test_encrypted_session() {
+        let path = std::env::temp_dir().join(format!(
+            "matrix_bot_test_session-{}.json",
+            uuid::Uuid::new_v4().simple()
+        ));
+        let session = Session {
+            access_token: "token".to_string(),
+            refresh_token: None,
+            user_id: user_id!("@bot:example.org").to_owned(),
+            device_id: device_id!("DEVICE").to_owned(),
+        };
+
+        save(&path, &session, Some("secret")).unwrap();
+        assert!(!fs::read_to_string(&path).unwrap().contains("token"));
+        match load(&path, Some("secret")).unwrap() {
+            Loaded::Session(loaded) => assert_eq!(loaded.access_token, "token"),
+            _ => panic!("session not loaded"),
+        }
+        assert!(load(&path, Some("wrong")).is_err());
+        assert!(load(&path, None).is_err());
+
+        save(&path, &session, None).unwrap();
+        assert!(matches!(
+            load(&path, Some("secret")).unwrap(),
+            Loaded::Unencrypted(_)
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
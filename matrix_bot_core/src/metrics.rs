@@ -0,0 +1,150 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::plugin::supervisor;
+
+/// 超过该时间没有成功的 sync 视为 sync 已停止
+pub const SYNC_STALE_AFTER: Duration = Duration::from_secs(2 * 60);
+
+type Labels = Vec<(&'static str, String)>;
+
+static COUNTERS: OnceLock<Mutex<BTreeMap<&'static str, BTreeMap<Labels, u64>>>> = OnceLock::new();
+static LAST_SYNC: Mutex<Option<SystemTime>> = Mutex::new(None);
+
+// 核心指标的说明，插件自行增加的计数器只输出类型
+const HELP: &[(&str, &str)] = &[
+    (
+        "matrix_bot_messages_sent_total",
+        "Messages sent to Matrix rooms",
+    ),
+    (
+        "matrix_bot_messages_failed_total",
+        "Messages that failed to send after retries",
+    ),
+    ("matrix_bot_syncs_total", "Successful sync responses"),
+];
+
+fn counters() -> &'static Mutex<BTreeMap<&'static str, BTreeMap<Labels, u64>>> {
+    COUNTERS.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+/// 计数器加一，`name` 需以 `_total` 结尾
+pub fn inc(name: &'static str, labels: &[(&'static str, &str)]) {
+    add(name, labels, 1);
+}
+
+pub fn add(name: &'static str, labels: &[(&'static str, &str)], value: u64) {
+    let labels = labels
+        .iter()
+        .map(|(k, v)| (*k, v.to_string()))
+        .collect::<Labels>();
+    *counters()
+        .lock()
+        .unwrap()
+        .entry(name)
+        .or_default()
+        .entry(labels)
+        .or_default() += value;
+}
+
+/// 记录一次成功的 sync
+pub fn record_sync() {
+    *LAST_SYNC.lock().unwrap() = Some(SystemTime::now());
+    inc("matrix_bot_syncs_total", &[]);
+}
+
+pub fn last_sync() -> Option<SystemTime> {
+    *LAST_SYNC.lock().unwrap()
+}
+
+/// 最近一次 sync 是否在 [`SYNC_STALE_AFTER`] 之内
+pub fn sync_alive() -> bool {
+    last_sync()
+        .and_then(|t| t.elapsed().ok())
+        .is_some_and(|elapsed| elapsed < SYNC_STALE_AFTER)
+}
+
+/// 以 Prometheus 文本格式输出所有指标
+pub fn render() -> String {
+    let mut out = String::new();
+
+    for (name, series) in counters().lock().unwrap().iter() {
+        if let Some((_, help)) = HELP.iter().find(|(n, _)| n == name) {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+        }
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (labels, value) in series {
+            let _ = writeln!(out, "{}{} {}", name, format_labels(labels), value);
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP matrix_bot_last_sync_timestamp_seconds Time of the last successful sync"
+    );
+    let _ = writeln!(out, "# TYPE matrix_bot_last_sync_timestamp_seconds gauge");
+    let last_sync = last_sync()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    let _ = writeln!(out, "matrix_bot_last_sync_timestamp_seconds {}", last_sync);
+
+    let _ = writeln!(
+        out,
+        "# HELP matrix_bot_plugin_up Whether the plugin is starting or running"
+    );
+    let _ = writeln!(out, "# TYPE matrix_bot_plugin_up gauge");
+    for (name, status) in supervisor::status() {
+        let labels = vec![
+            ("plugin", name.to_string()),
+            ("state", status.state.as_str().to_string()),
+        ];
+        let _ = writeln!(
+            out,
+            "matrix_bot_plugin_up{} {}",
+            format_labels(&labels),
+            u8::from(status.state.is_healthy())
+        );
+    }
+
+    out
+}
+
+fn format_labels(labels: &Labels) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", k, v)
+        })
+        .collect::<Vec<_>>();
+    format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        inc("matrix_bot_messages_sent_total", &[("plugin", "webhook")]);
+        inc("matrix_bot_messages_sent_total", &[("plugin", "webhook")]);
+        inc("test_total", &[("name", "a\"b")]);
+
+        let text = render();
+        assert!(text.contains("# HELP matrix_bot_messages_sent_total"));
+        assert!(text.contains("matrix_bot_messages_sent_total{plugin=\"webhook\"} 2\n"));
+        assert!(text.contains("# TYPE test_total counter\ntest_total{name=\"a\\\"b\"} 1\n"));
+        assert!(text.contains("matrix_bot_last_sync_timestamp_seconds 0\n"));
+        assert!(!sync_alive());
+    }
+}
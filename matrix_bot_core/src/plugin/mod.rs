@@ -0,0 +1,523 @@
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::{config::Config, matrix::client::Client};
+
+pub use supervisor::{State, Status};
+pub use tokio_util::sync::CancellationToken;
+
+pub mod supervisor;
+
+tokio::task_local! {
+    static CURRENT: &'static str;
+}
+
+/// 当前任务所属的插件，用于按插件统计指标
+pub fn current() -> Option<&'static str> {
+    CURRENT.try_with(|name| *name).ok()
+}
+
+/// 以指定插件的身份运行，插件中 `tokio::spawn` 的任务需要重新设置
+pub async fn scope<F: Future>(name: &'static str, f: F) -> F::Output {
+    CURRENT.scope(name, f).await
+}
+
+/// 配置热重载的处理结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reload {
+    /// 插件已在运行中应用新配置
+    Applied,
+    /// 需要停止插件并使用新配置重新启动
+    Restart,
+}
+
+/// 插件接口，每个插件实现该 trait 后注册到 [`Registry`] 中即可被加载
+#[async_trait]
+pub trait Plugin: Send + Sync + 'static {
+    /// 插件配置，对应配置文件中的 `[plugins.<name>]`
+    type Setting: Serialize + DeserializeOwned + Send + 'static;
+
+    /// 插件名称，同时用于配置文件与 `--plugins` 参数
+    fn name(&self) -> &'static str;
+
+    /// 默认配置，写入配置文件供用户修改
+    fn default_setting(&self, plugin_folder: &Path) -> Self::Setting;
+
+    /// 校验配置，`config check` 时也会调用，不能依赖登录状态
+    fn validate(&self, _setting: &Self::Setting) -> Result<()> {
+        Ok(())
+    }
+
+    /// 运行前的准备工作
+    async fn init(&self, _client: &Client, _setting: &Self::Setting) -> Result<()> {
+        Ok(())
+    }
+
+    /// 插件主循环，返回即视为插件停止
+    ///
+    /// `shutdown` 被取消后应尽快结束当前操作并返回，超过 `core.shutdown_timeout` 仍未返回的插件会被强制中止
+    async fn run(
+        &self,
+        client: Client,
+        setting: Self::Setting,
+        shutdown: CancellationToken,
+    ) -> Result<()>;
+
+    /// 配置文件中该插件的配置发生变化时调用，`new` 已经通过校验
+    ///
+    /// 返回 [`Reload::Applied`] 表示插件已自行应用新配置，默认重启插件
+    async fn reload(
+        &self,
+        _client: &Client,
+        _old: &Self::Setting,
+        _new: &Self::Setting,
+    ) -> Result<Reload> {
+        Ok(Reload::Restart)
+    }
+
+    /// 插件停止时的清理工作，插件被强制中止后也会调用
+    ///
+    /// 插件自行创建的线程不会随任务中止，需要在这里通知它们退出并等待结束
+    async fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+trait DynPlugin: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn default_section(&self, plugin_folder: &Path) -> Result<toml::Value>;
+
+    fn check(&self, config: &Config) -> Result<()>;
+
+    async fn start(
+        &self,
+        client: Client,
+        config: Arc<Config>,
+        shutdown: CancellationToken,
+    ) -> Result<()>;
+
+    async fn reload(&self, client: &Client, old: &Config, new: &Config) -> Result<Reload>;
+
+    async fn shutdown(&self) -> Result<()>;
+}
+
+fn load<P: Plugin>(plugin: &P, config: &Config) -> Result<P::Setting> {
+    let setting = config
+        .plugin::<P::Setting>(plugin.name())?
+        .ok_or(anyhow!("missing section [plugins.{}]", plugin.name()))?;
+    plugin
+        .validate(&setting)
+        .map_err(|e| anyhow!("[plugins.{}]: {}", plugin.name(), e))?;
+    Ok(setting)
+}
+
+#[async_trait]
+impl<P: Plugin> DynPlugin for P {
+    fn name(&self) -> &'static str {
+        Plugin::name(self)
+    }
+
+    fn default_section(&self, plugin_folder: &Path) -> Result<toml::Value> {
+        Ok(toml::Value::try_from(self.default_setting(plugin_folder))?)
+    }
+
+    fn check(&self, config: &Config) -> Result<()> {
+        load(self, config).map(|_| ())
+    }
+
+    async fn start(
+        &self,
+        client: Client,
+        config: Arc<Config>,
+        shutdown: CancellationToken,
+    ) -> Result<()> {
+        let setting = load(self, &config)?;
+        self.init(&client, &setting).await?;
+        supervisor::set_state(Plugin::name(self), State::Running);
+        self.run(client, setting, shutdown).await
+    }
+
+    async fn reload(&self, client: &Client, old: &Config, new: &Config) -> Result<Reload> {
+        let new = load(self, new)?;
+        // 旧配置无法解析时（例如插件结构变化）直接重启
+        let Ok(Some(old)) = old.plugin::<P::Setting>(Plugin::name(self)) else {
+            return Ok(Reload::Restart);
+        };
+        scope(Plugin::name(self), Plugin::reload(self, client, &old, &new)).await
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        Plugin::shutdown(self).await
+    }
+}
+
+#[derive(Default)]
+pub struct Registry {
+    plugins: Vec<Arc<dyn DynPlugin>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, plugin: impl Plugin) -> &mut Self {
+        if self.names().contains(&plugin.name()) {
+            log::warn!("plugin {} already registered, skip", plugin.name());
+            return self;
+        }
+        self.plugins.push(Arc::new(plugin));
+        self
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.plugins.iter().map(|p| p.name()).collect()
+    }
+
+    fn enabled<'a>(&'a self, config: &'a Config) -> impl Iterator<Item = &'a Arc<dyn DynPlugin>> {
+        for name in config.core.plugins.iter().filter(|s| *s != "all") {
+            if !self.names().contains(&name.as_str()) {
+                log::warn!("unknown plugin: {}", name);
+            }
+        }
+        self.plugins
+            .iter()
+            .filter(|p| config.core.plugin_enabled(p.name()))
+    }
+
+    /// 生成包含所有插件默认配置的配置文件，旧版的 `<plugin>.toml` 会被合并进来
+    pub fn write_default_config(
+        &self,
+        path: impl AsRef<Path>,
+        plugin_folder: impl AsRef<Path>,
+    ) -> Result<()> {
+        let mut sections = Vec::new();
+        for plugin in &self.plugins {
+            let legacy = plugin_folder
+                .as_ref()
+                .join(format!("{}.toml", plugin.name()));
+            let section = if legacy.exists() {
+                log::info!("import setting file: {}", legacy.to_string_lossy());
+                toml::from_str(&std::fs::read_to_string(&legacy)?)?
+            } else {
+                plugin.default_section(plugin_folder.as_ref())?
+            };
+            sections.push((plugin.name().to_string(), section));
+        }
+        crate::config::write_default(path, sections)
+    }
+
+    /// 校验所有启用插件的配置，返回每个插件的结果
+    pub fn check(&self, config: &Config) -> Vec<(&'static str, Result<()>)> {
+        self.enabled(config)
+            .map(|p| (p.name(), p.check(config)))
+            .collect()
+    }
+
+    /// 启动配置中启用的插件，缺少配置的插件会在配置文件中追加默认配置
+    ///
+    /// `shutdown` 被取消后所有插件停止，[`Plugins::watch`] 返回的任务在插件全部退出后结束
+    pub fn start(
+        self,
+        client: &Client,
+        config: Arc<Config>,
+        plugin_folder: impl AsRef<Path>,
+        shutdown: CancellationToken,
+    ) -> Result<Plugins> {
+        std::fs::create_dir_all(&plugin_folder)?;
+
+        let mut running = Vec::new();
+        for plugin in self.enabled(&config) {
+            let mut entry = Entry {
+                plugin: plugin.clone(),
+                config: None,
+                handle: None,
+                shutdown: shutdown.child_token(),
+            };
+            if config.has_plugin(plugin.name()) {
+                entry.spawn(client, config.clone());
+            } else {
+                config.append_plugin(
+                    plugin.name(),
+                    plugin.default_section(plugin_folder.as_ref())?,
+                )?;
+                log::error!(
+                    "please edit section [plugins.{}] in {}",
+                    plugin.name(),
+                    config.path().to_string_lossy()
+                );
+            }
+            running.push(entry);
+        }
+
+        Ok(Plugins {
+            client: client.clone(),
+            path: config.path().to_path_buf(),
+            config,
+            running,
+            shutdown,
+            control: mpsc::unbounded_channel(),
+        })
+    }
+}
+
+struct Entry {
+    plugin: Arc<dyn DynPlugin>,
+    // 插件当前使用的配置，未启动时为 `None`
+    config: Option<Arc<Config>>,
+    handle: Option<JoinHandle<()>>,
+    // 只用于停止该插件，全局停止时随父 token 一起取消
+    shutdown: CancellationToken,
+}
+
+impl Entry {
+    fn spawn(&mut self, client: &Client, config: Arc<Config>) {
+        self.config = Some(config.clone());
+        self.handle = Some(tokio::spawn(supervisor::supervise(
+            self.plugin.clone(),
+            client.clone(),
+            config,
+            self.shutdown.clone(),
+        )));
+    }
+
+    /// 通知插件停止并等待到 `deadline`，超时后强制中止
+    async fn stop(&mut self, deadline: Instant) {
+        self.shutdown.cancel();
+        let Some(mut handle) = self.handle.take() else {
+            return;
+        };
+        if tokio::time::timeout_at(deadline, &mut handle).await.is_ok() {
+            return;
+        }
+
+        let name = self.plugin.name();
+        log::warn!("{} did not stop in time, abort", name);
+        handle.abort();
+        let _ = handle.await;
+        if let Err(e) = self.plugin.shutdown().await {
+            log::error!("{} shutdown failed: {}", name, e);
+        }
+        log::info!("{} stopped", name);
+        supervisor::set_state(name, State::Stopped);
+    }
+
+    /// 使用当前配置重启插件
+    async fn restart(&mut self, client: &Client, parent: &CancellationToken) -> Result<()> {
+        let name = self.plugin.name();
+        let config = self
+            .config
+            .clone()
+            .ok_or(anyhow!("{} is not configured", name))?;
+        log::info!("restart {}", name);
+        self.stop(Instant::now() + shutdown_timeout(&config)).await;
+        self.shutdown = parent.child_token();
+        self.spawn(client, config);
+        Ok(())
+    }
+
+    async fn reload(&mut self, client: &Client, config: &Arc<Config>, parent: &CancellationToken) {
+        let name = self.plugin.name();
+        let path = ["plugins", name];
+        let old = self.config.clone();
+        if old.as_ref().and_then(|c| c.value(&path)) == config.value(&path) {
+            return;
+        }
+        if !config.has_plugin(name) {
+            log::warn!("section [plugins.{}] removed, keep running", name);
+            return;
+        }
+        if let Err(e) = self.plugin.check(config) {
+            log::error!("reload {} rejected, keep old config: {}", name, e);
+            return;
+        }
+
+        // 未在运行（启动中、等待重启或已失败）时直接使用新配置重启
+        let running = supervisor::state(name) == Some(State::Running);
+        let reload = match &old {
+            Some(old) if running => self.plugin.reload(client, old, config).await,
+            _ => Ok(Reload::Restart),
+        };
+        match reload {
+            Ok(Reload::Applied) => {
+                log::info!("{} config reloaded", name);
+                self.config = Some(config.clone());
+            }
+            Ok(Reload::Restart) => {
+                log::info!("restart {} with new config", name);
+                self.stop(Instant::now() + shutdown_timeout(config)).await;
+                self.shutdown = parent.child_token();
+                self.spawn(client, config.clone());
+            }
+            Err(e) => log::error!("reload {} rejected, keep old config: {}", name, e),
+        }
+    }
+}
+
+enum Control {
+    Reload(oneshot::Sender<Result<()>>),
+    Restart(String, oneshot::Sender<Result<()>>),
+}
+
+/// 在其他任务中控制已启动的插件，例如管理命令
+#[derive(Clone)]
+pub struct PluginsHandle {
+    tx: mpsc::UnboundedSender<Control>,
+}
+
+impl PluginsHandle {
+    /// 重新读取配置文件
+    pub async fn reload(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Control::Reload(tx))?;
+        rx.await?
+    }
+
+    /// 使用当前配置重启插件
+    pub async fn restart(&self, name: &str) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Control::Restart(name.to_string(), tx))?;
+        rx.await?
+    }
+
+    fn send(&self, control: Control) -> Result<()> {
+        self.tx
+            .send(control)
+            .map_err(|_| anyhow!("plugins already stopped"))
+    }
+}
+
+/// 已启动的插件，监听配置文件变化并热重载
+pub struct Plugins {
+    client: Client,
+    path: PathBuf,
+    config: Arc<Config>,
+    running: Vec<Entry>,
+    shutdown: CancellationToken,
+    control: (
+        mpsc::UnboundedSender<Control>,
+        mpsc::UnboundedReceiver<Control>,
+    ),
+}
+
+impl Plugins {
+    pub fn handle(&self) -> PluginsHandle {
+        PluginsHandle {
+            tx: self.control.0.clone(),
+        }
+    }
+
+    /// 使用当前配置重启插件
+    pub async fn restart(&mut self, name: &str) -> Result<()> {
+        let entry = self
+            .running
+            .iter_mut()
+            .find(|e| e.plugin.name() == name)
+            .ok_or(anyhow!("plugin {} is not enabled", name))?;
+        entry.restart(&self.client, &self.shutdown).await
+    }
+
+    /// 重新读取配置文件，将变化的配置交给对应插件，无法解析时保留旧配置
+    pub async fn reload(&mut self) -> Result<()> {
+        let config = Arc::new(self.config.reload()?);
+        for entry in self.running.iter_mut() {
+            entry.reload(&self.client, &config, &self.shutdown).await;
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    /// 停止所有插件，等待时间超过 `core.shutdown_timeout` 的插件会被强制中止
+    async fn drain(&mut self) {
+        let timeout = shutdown_timeout(&self.config);
+        log::info!("stopping plugins, wait up to {}s", timeout.as_secs());
+        // 所有插件的 token 都已随父 token 取消，依次等待到同一截止时间即可
+        let deadline = Instant::now() + timeout;
+        for entry in self.running.iter_mut() {
+            entry.stop(deadline).await;
+        }
+        log::info!("all plugins stopped");
+    }
+
+    /// 轮询配置文件的修改时间，并在收到 SIGHUP 时重新加载，停止信号到达后等待插件退出
+    pub fn watch(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let mut hangup =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+
+            let mut modified = modified_time(&self.path);
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            loop {
+                #[cfg(unix)]
+                let hangup = async {
+                    match hangup.as_mut() {
+                        Some(signal) => signal.recv().await,
+                        None => std::future::pending().await,
+                    }
+                };
+                #[cfg(not(unix))]
+                let hangup = std::future::pending::<Option<()>>();
+
+                let control = tokio::select! {
+                    _ = self.shutdown.cancelled() => break,
+                    _ = hangup => {
+                        log::info!("SIGHUP received, reload config");
+                        None
+                    }
+                    Some(control) = self.control.1.recv() => Some(control),
+                    _ = interval.tick() => {
+                        let current = modified_time(&self.path);
+                        if current == modified {
+                            continue;
+                        }
+                        modified = current;
+                        log::info!("config file changed, reload config");
+                        None
+                    }
+                };
+
+                match control {
+                    None => {
+                        if let Err(e) = self.reload().await {
+                            log::error!("reload config failed, keep old config: {}", e);
+                        }
+                    }
+                    Some(Control::Reload(reply)) => {
+                        let _ = reply.send(self.reload().await);
+                    }
+                    Some(Control::Restart(name, reply)) => {
+                        let _ = reply.send(self.restart(&name).await);
+                    }
+                }
+            }
+
+            self.drain().await;
+        })
+    }
+}
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+fn shutdown_timeout(config: &Config) -> Duration {
+    Duration::from_secs(config.core.shutdown_timeout)
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -0,0 +1,329 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, OnceLock, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use tokio::task::JoinHandle;
+
+use super::{scope, CancellationToken, DynPlugin};
+use crate::{config::Config, matrix::client::Client};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+// 连续失败超过该次数后不再重启，等待配置修改
+const MAX_RESTARTS: u32 = 10;
+// 运行超过该时间后视为恢复正常，重新计算失败次数
+const STABLE_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// 插件运行状态
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum State {
+    Starting,
+    Running,
+    /// 运行失败，等待重启
+    Backoff {
+        attempt: u32,
+        error: String,
+        retry_at: SystemTime,
+    },
+    /// 连续失败次数过多，不再重启
+    Failed(String),
+    Stopped,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Starting => "starting",
+            State::Running => "running",
+            State::Backoff { .. } => "backoff",
+            State::Failed(_) => "failed",
+            State::Stopped => "stopped",
+        }
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            State::Backoff { error, .. } | State::Failed(error) => Some(error),
+            _ => None,
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, State::Starting | State::Running)
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Backoff {
+                attempt,
+                error,
+                retry_at,
+            } => {
+                let retry_in = retry_at
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default();
+                write!(
+                    f,
+                    "backoff (attempt {}, retry in {}s): {}",
+                    attempt,
+                    retry_in.as_secs(),
+                    error
+                )
+            }
+            State::Failed(error) => write!(f, "failed: {}", error),
+            state => f.write_str(state.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub state: State,
+    /// 进入当前状态的时间
+    pub since: SystemTime,
+    /// 自进程启动以来的重启次数
+    pub restarts: u32,
+}
+
+static STATES: OnceLock<RwLock<BTreeMap<&'static str, Status>>> = OnceLock::new();
+
+fn states() -> &'static RwLock<BTreeMap<&'static str, Status>> {
+    STATES.get_or_init(|| RwLock::new(BTreeMap::new()))
+}
+
+pub(super) fn set_state(name: &'static str, state: State) {
+    let mut states = states().write().unwrap();
+    let restarts = states.get(name).map_or(0, |s| s.restarts);
+    let restarts = match state {
+        State::Backoff { .. } => restarts + 1,
+        _ => restarts,
+    };
+    states.insert(
+        name,
+        Status {
+            state,
+            since: SystemTime::now(),
+            restarts,
+        },
+    );
+}
+
+/// 插件当前状态
+pub fn state(name: &str) -> Option<State> {
+    states().read().unwrap().get(name).map(|s| s.state.clone())
+}
+
+/// 所有已启动插件的状态
+pub fn status() -> BTreeMap<&'static str, Status> {
+    states().read().unwrap().clone()
+}
+
+/// 所有插件都处于正常状态
+pub fn healthy() -> bool {
+    states()
+        .read()
+        .unwrap()
+        .values()
+        .all(|s| s.state.is_healthy() || s.state == State::Stopped)
+}
+
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+// 中止监督任务时同时中止正在运行的插件
+struct AbortOnDrop(JoinHandle<Result<()>>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// 运行插件，失败或 panic 时按指数退避重启，`shutdown` 取消后不再重启
+pub(super) async fn supervise(
+    plugin: Arc<dyn DynPlugin>,
+    client: Client,
+    config: Arc<Config>,
+    shutdown: CancellationToken,
+) {
+    let name = plugin.name();
+    let mut attempt = 0;
+    loop {
+        set_state(name, State::Starting);
+        log::info!("start {}", name);
+        let started = Instant::now();
+
+        let mut task = AbortOnDrop(tokio::spawn({
+            let plugin = plugin.clone();
+            let client = client.clone();
+            let config = config.clone();
+            let shutdown = shutdown.clone();
+            async move { scope(name, plugin.start(client, config, shutdown)).await }
+        }));
+        let result = match (&mut task.0).await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => Err(anyhow!("panicked: {}", panic_message(e.into_panic()))),
+            Err(e) => Err(anyhow!(e)),
+        };
+
+        if let Err(e) = plugin.shutdown().await {
+            log::error!("{} shutdown failed: {}", name, e);
+        }
+
+        if shutdown.is_cancelled() {
+            if let Err(e) = &result {
+                log::error!("{} stop: {}", name, e);
+            }
+            log::info!("{} stopped", name);
+            set_state(name, State::Stopped);
+            return;
+        }
+
+        let error = match result {
+            Ok(_) => {
+                log::info!("{} exited", name);
+                set_state(name, State::Stopped);
+                return;
+            }
+            Err(e) => e.to_string(),
+        };
+        log::error!("{} stop: {}", name, error);
+
+        if started.elapsed() >= STABLE_AFTER {
+            attempt = 0;
+        }
+        attempt += 1;
+        if attempt > MAX_RESTARTS {
+            log::error!("{} failed {} times, give up", name, MAX_RESTARTS);
+            set_state(name, State::Failed(error));
+            return;
+        }
+
+        let delay = backoff(attempt);
+        log::info!("restart {} in {}s", name, delay.as_secs());
+        set_state(
+            name,
+            State::Backoff {
+                attempt,
+                error,
+                retry_at: SystemTime::now() + delay,
+            },
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.cancelled() => {
+                set_state(name, State::Stopped);
+                return;
+            }
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::plugin::Plugin;
+
+    #[derive(Default)]
+    struct Flaky {
+        runs: AtomicU32,
+    }
+
+    #[async_trait]
+    impl Plugin for Flaky {
+        type Setting = toml::Table;
+
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn default_setting(&self, _plugin_folder: &Path) -> toml::Table {
+            toml::Table::new()
+        }
+
+        async fn run(
+            &self,
+            _client: Client,
+            _setting: toml::Table,
+            shutdown: CancellationToken,
+        ) -> Result<()> {
+            if self.runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("first run");
+            }
+            shutdown.cancelled().await;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(5), Duration::from_secs(16));
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(100), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_restart_after_panic() {
+        let path = std::env::temp_dir().join(format!(
+            "matrix_bot_test_config-{}.toml",
+            uuid::Uuid::new_v4().simple()
+        ));
+        std::fs::write(&path, "[plugins.flaky]\n").unwrap();
+        let config = Arc::new(Config::load(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let client = matrix_sdk::Client::builder()
+            .homeserver_url("http://localhost")
+            .build()
+            .await
+            .unwrap();
+
+        let plugin = Arc::new(Flaky::default());
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(supervise(
+            plugin.clone(),
+            Client(client),
+            config,
+            shutdown.clone(),
+        ));
+
+        // 第一次运行 panic，退避后重新启动
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while plugin.runs.load(Ordering::SeqCst) < 2 || state("flaky") != Some(State::Running) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(status()["flaky"].restarts, 1);
+
+        shutdown.cancel();
+        task.await.unwrap();
+        assert_eq!(state("flaky"), Some(State::Stopped));
+    }
+}
@@ -0,0 +1,346 @@
+//! 插件消息模板：每个模板包含纯文本与可选的 HTML 两种正文，可以在房间配置中覆盖
+//!
+//! 语法是 Mustache 的子集：
+//! - `{{name}}` 插入变量，HTML 正文中会转义
+//! - `{{#name}}...{{/name}}` 变量为列表时逐项渲染，为真时渲染一次
+//! - `{{^name}}...{{/name}}` 变量不存在、为空或为假时渲染
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// 插件自带的默认模板
+#[derive(Debug, Clone, Copy)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub plain: &'static str,
+    pub html: Option<&'static str>,
+}
+
+/// 配置中的模板，不设置 `html` 时只发送纯文本
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Template {
+    pub plain: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+}
+
+/// 房间配置中按名称覆盖的模板，例如 `[plugins.qbittorrent.room.templates.finished]`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Overrides(BTreeMap<String, Template>);
+
+impl Overrides {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// 渲染结果，`html` 为 `None` 时作为纯文本发送
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub plain: String,
+    pub html: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Text(String),
+    Bool(bool),
+    List(Vec<Vars>),
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<Vec<Vars>> for Value {
+    fn from(value: Vec<Vars>) -> Self {
+        Value::List(value)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Bool(false), Into::into)
+    }
+}
+
+/// 模板变量
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Vars(HashMap<String, Value>);
+
+impl Vars {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.0.insert(name.to_string(), value.into());
+        self
+    }
+}
+
+/// 插件的模板集合
+#[derive(Debug, Clone, Copy)]
+pub struct Templates(&'static [Builtin]);
+
+impl Templates {
+    pub const fn new(builtin: &'static [Builtin]) -> Self {
+        Templates(builtin)
+    }
+
+    /// 使用房间的覆盖模板或默认模板渲染
+    pub fn render(&self, name: &str, overrides: &Overrides, vars: &Vars) -> Result<Message> {
+        let (plain, html) = match overrides.0.get(name) {
+            Some(template) => (template.plain.as_str(), template.html.as_deref()),
+            None => {
+                let builtin = self
+                    .0
+                    .iter()
+                    .find(|b| b.name == name)
+                    .ok_or(anyhow!("unknown template `{}`", name))?;
+                (builtin.plain, builtin.html)
+            }
+        };
+        Ok(Message {
+            plain: render(plain, vars, false)?,
+            html: html.map(|html| render(html, vars, true)).transpose()?,
+        })
+    }
+
+    /// 检查覆盖的模板名称与语法，用于校验配置
+    pub fn validate(&self, overrides: &Overrides) -> Result<()> {
+        for (name, template) in &overrides.0 {
+            if !self.0.iter().any(|b| b.name == name) {
+                let names = self.0.iter().map(|b| b.name).collect::<Vec<_>>();
+                return Err(anyhow!(
+                    "unknown template `{}`, available: {}",
+                    name,
+                    names.join(", ")
+                ));
+            }
+            parse(&template.plain).map_err(|e| anyhow!("template `{}`: {}", name, e))?;
+            if let Some(html) = &template.html {
+                parse(html).map_err(|e| anyhow!("template `{}`: {}", name, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Node<'a> {
+    Text(&'a str),
+    Var(&'a str),
+    Section {
+        name: &'a str,
+        inverted: bool,
+        children: Vec<Node<'a>>,
+    },
+}
+
+fn parse(src: &str) -> Result<Vec<Node<'_>>> {
+    // 未闭合的区块：名称、是否反转与已解析的内容
+    let mut stack: Vec<(&str, bool, Vec<Node>)> = Vec::new();
+    let mut nodes = Vec::new();
+    let mut rest = src;
+
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            nodes.push(Node::Text(&rest[..start]));
+        }
+        let end = rest[start..].find("}}").ok_or(anyhow!(
+            "unclosed tag at byte {}",
+            src.len() - rest.len() + start
+        ))?;
+        let tag = rest[start + 2..start + end].trim();
+        rest = &rest[start + end + 2..];
+
+        match tag.chars().next() {
+            Some(c @ ('#' | '^')) => {
+                let name = tag[1..].trim();
+                stack.push((name, c == '^', std::mem::take(&mut nodes)));
+            }
+            Some('/') => {
+                let name = tag[1..].trim();
+                let (open, inverted, parent) = stack
+                    .pop()
+                    .ok_or(anyhow!("unexpected closing tag `{}`", name))?;
+                if open != name {
+                    return Err(anyhow!("`{}` is closed by `{}`", open, name));
+                }
+                let children = std::mem::replace(&mut nodes, parent);
+                nodes.push(Node::Section {
+                    name,
+                    inverted,
+                    children,
+                });
+            }
+            Some(_) => nodes.push(Node::Var(tag)),
+            None => return Err(anyhow!("empty tag")),
+        }
+    }
+    if let Some((name, _, _)) = stack.last() {
+        return Err(anyhow!("unclosed section `{}`", name));
+    }
+    if !rest.is_empty() {
+        nodes.push(Node::Text(rest));
+    }
+    Ok(nodes)
+}
+
+fn render(src: &str, vars: &Vars, html: bool) -> Result<String> {
+    let mut out = String::new();
+    render_nodes(&parse(src)?, &mut vec![vars], html, &mut out);
+    Ok(out)
+}
+
+fn render_nodes<'a>(nodes: &[Node], scopes: &mut Vec<&'a Vars>, html: bool, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var(name) => match lookup(scopes, name) {
+                Some(Value::Text(text)) if html => out.push_str(&escape_html(text)),
+                Some(Value::Text(text)) => out.push_str(text),
+                Some(Value::Bool(true)) => out.push_str("true"),
+                _ => {}
+            },
+            Node::Section {
+                name,
+                inverted,
+                children,
+            } => {
+                let value = lookup(scopes, name);
+                let truthy = match value {
+                    Some(Value::Text(text)) => !text.is_empty(),
+                    Some(Value::Bool(b)) => *b,
+                    Some(Value::List(list)) => !list.is_empty(),
+                    None => false,
+                };
+                if *inverted {
+                    if !truthy {
+                        render_nodes(children, scopes, html, out);
+                    }
+                    continue;
+                }
+                match value {
+                    Some(Value::List(list)) => {
+                        for item in list {
+                            scopes.push(item);
+                            render_nodes(children, scopes, html, out);
+                            scopes.pop();
+                        }
+                    }
+                    _ if truthy => render_nodes(children, scopes, html, out),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// 从内层向外查找变量
+fn lookup<'a>(scopes: &[&'a Vars], name: &str) -> Option<&'a Value> {
+    scopes.iter().rev().find_map(|vars| vars.0.get(name))
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATES: Templates = Templates::new(&[Builtin {
+        name: "status",
+        plain: "{{#rows}}{{name}} {{progress}}\n{{/rows}}{{^rows}}没有任务{{/rows}}",
+        html: Some("<ul>{{#rows}}<li>{{name}}</li>{{/rows}}</ul>"),
+    }]);
+
+    #[test]
+    fn test_render() {
+        let vars = Vars::new().set(
+            "rows",
+            vec![
+                Vars::new().set("name", "<a&b>").set("progress", "50.00%"),
+                Vars::new().set("name", "c").set("progress", "100.00%"),
+            ],
+        );
+        let msg = TEMPLATES
+            .render("status", &Overrides::default(), &vars)
+            .unwrap();
+        assert_eq!(msg.plain, "<a&b> 50.00%\nc 100.00%\n");
+        assert_eq!(
+            msg.html.as_deref(),
+            Some("<ul><li>&lt;a&amp;b&gt;</li><li>c</li></ul>")
+        );
+
+        let msg = TEMPLATES
+            .render("status", &Overrides::default(), &Vars::new())
+            .unwrap();
+        assert_eq!(msg.plain, "没有任务");
+
+        // 覆盖的模板没有 html 时只发送纯文本，内层找不到的变量从外层查找
+        let overrides: Overrides = toml::from_str(
+            r#"
+            [status]
+            plain = "{{#rows}}{{title}}: {{name}};{{/rows}}"
+            "#,
+        )
+        .unwrap();
+        TEMPLATES.validate(&overrides).unwrap();
+        let vars = vars.set("title", "任务");
+        let msg = TEMPLATES.render("status", &overrides, &vars).unwrap();
+        assert_eq!(msg.plain, "任务: <a&b>;任务: c;");
+        assert_eq!(msg.html, None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let overrides: Overrides = toml::from_str(
+            r#"
+            [unknown]
+            plain = "x"
+            "#,
+        )
+        .unwrap();
+        assert!(TEMPLATES.validate(&overrides).is_err());
+
+        for src in ["{{#rows}}", "{{/rows}}", "{{#a}}{{/b}}", "{{name", "{{}}"] {
+            assert!(parse(src).is_err(), "{}", src);
+        }
+        assert_eq!(
+            parse("a {{ name }}").unwrap(),
+            vec![Node::Text("a "), Node::Var("name")]
+        );
+    }
+}
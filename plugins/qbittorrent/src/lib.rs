@@ -1,7 +1,14 @@
-use std::{collections::HashMap, sync::OnceLock};
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock, RwLock},
+};
 
 use anyhow::Result;
-use matrix_bot_core::matrix::{client::Client, room::Room};
+use matrix_bot_core::{
+    async_trait,
+    matrix::{client::Client, room::Room},
+    plugin::{CancellationToken, Plugin, Reload},
+};
 use qbit_rs::Qbit;
 use setting::RoomSetting;
 
@@ -13,61 +20,127 @@ use crate::{
 mod matrix;
 mod qbit;
 mod setting;
+mod template;
 mod upload;
 
-static ROOM_MAP: OnceLock<HashMap<String, (Room, RoomSetting)>> = OnceLock::new();
-static API: OnceLock<Qbit> = OnceLock::new();
+static ROOM_MAP: OnceLock<RwLock<HashMap<String, (Room, RoomSetting)>>> = OnceLock::new();
+static API: RwLock<Option<Arc<Qbit>>> = RwLock::new(None);
+// 内置的 qbittorrent-nox，插件停止或被中止后在 `shutdown` 中关闭
+#[cfg(target_os = "linux")]
+static PROCESS: std::sync::Mutex<Option<qbit::binary::Process>> = std::sync::Mutex::new(None);
 
-#[allow(unused_variables)]
-pub async fn run(client: Client, plugin_folder: impl AsRef<std::path::Path>) -> Result<()> {
-    log::info!("start qbittorrent plugin");
+fn room_map() -> &'static RwLock<HashMap<String, (Room, RoomSetting)>> {
+    ROOM_MAP.get_or_init(|| RwLock::new(HashMap::new()))
+}
 
-    let setting = Setting::get_or_init(plugin_folder)?;
+/// 按房间 ID 获取房间与对应配置
+pub(crate) fn get_room(room_id: &str) -> Option<(Room, RoomSetting)> {
+    room_map().read().unwrap().get(room_id).cloned()
+}
 
-    #[cfg(target_os = "linux")]
-    let _child: std::process::Child;
+pub(crate) fn api() -> Result<Arc<Qbit>> {
+    API.read()
+        .unwrap()
+        .clone()
+        .ok_or(anyhow::anyhow!("qbittorrent not logged in"))
+}
+
+// 更新房间列表并重新注册命令
+fn set_rooms(rooms: HashMap<String, (Room, RoomSetting)>) {
+    let access = rooms
+        .values()
+        .map(|(room, setting)| (room.0.room_id().to_owned(), setting.access.clone()))
+        .collect();
+    *room_map().write().unwrap() = rooms;
+    matrix::register_commands(access);
+}
+
+pub struct Qbittorrent;
+
+#[async_trait]
+impl Plugin for Qbittorrent {
+    type Setting = Setting;
+
+    fn name(&self) -> &'static str {
+        "qbittorrent"
+    }
+
+    fn default_setting(&self, plugin_folder: &std::path::Path) -> Setting {
+        Setting::new(plugin_folder)
+    }
+
+    fn validate(&self, setting: &Setting) -> Result<()> {
+        setting.validate()
+    }
+
+    async fn run(
+        &self,
+        client: Client,
+        setting: Setting,
+        shutdown: CancellationToken,
+    ) -> Result<()> {
+        run(client, setting, shutdown).await
+    }
+
+    async fn reload(&self, client: &Client, old: &Setting, new: &Setting) -> Result<Reload> {
+        // 修改连接信息需要重新登录，只修改房间时不中断正在进行的上传
+        if !old.same_connection(new) {
+            return Ok(Reload::Restart);
+        }
+        set_rooms(new.to_hashmap(client).await?);
+        Ok(Reload::Applied)
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            let process = PROCESS.lock().unwrap().take();
+            if let Some(process) = process {
+                process.stop().await;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[allow(unused_variables)]
+async fn run(client: Client, setting: Setting, shutdown: CancellationToken) -> Result<()> {
     #[cfg(target_os = "linux")]
     if setting.use_internal_qbit {
         let runtime_folder = std::path::PathBuf::from("data/plugins/qbittorrent/runtime");
         let port = setting.qbit_url.split(":").last();
         let port = port.unwrap_or("80").parse().unwrap_or(80);
-        _child = qbit::binary::run(&runtime_folder, port)?;
+        // 首次运行需要下载 qbittorrent-nox
+        let child =
+            tokio::task::spawn_blocking(move || qbit::binary::run(&runtime_folder, port)).await??;
+        *PROCESS.lock().unwrap() = Some(qbit::binary::Process::new(child));
     }
 
-    let room = setting.to_hashmap(&client).await?;
-    ROOM_MAP
-        .set(room)
-        .map_err(|_| anyhow::anyhow!("ROOM_MAP OnceLock double set"))?;
+    let api = Arc::new(
+        qbit::ops::login(&setting.qbit_user, &setting.qbit_pass, &setting.qbit_url).await?,
+    );
+    *API.write().unwrap() = Some(api.clone());
 
-    let api = qbit::ops::login(&setting.qbit_user, &setting.qbit_pass, &setting.qbit_url).await?;
-    API.set(api)
-        .map_err(|_| anyhow::anyhow!("API OnceLock double set"))?;
-
-    for (room, setting) in ROOM_MAP.get().unwrap().values() {
-        matrix::add_listener(room);
-    }
+    set_rooms(setting.to_hashmap(&client).await?);
 
     loop {
-        let (expire, upload) = qbit::ops::scan_torrent(API.get().unwrap()).await.unwrap_or_else(
-            |e| {
-                log::error!("scan torrent failed: {}", e);
-                (HashMap::new(), HashMap::new())
-            }
-        );
-
+        let (expire, upload) = qbit::ops::scan_torrent(&api).await.unwrap_or_else(|e| {
+            log::error!("scan torrent failed: {}", e);
+            (HashMap::new(), HashMap::new())
+        });
 
-        expire_torrents(API.get().unwrap(), &expire)
-            .await
-            .unwrap_or_else(|e| {
-                log::error!("expire torrent failed: {}", e);
-            });
+        expire_torrents(&api, &expire).await.unwrap_or_else(|e| {
+            log::error!("expire torrent failed: {}", e);
+        });
 
-        upload_torrents(API.get().unwrap(), &upload)
-            .await
-            .unwrap_or_else(|e| {
-                log::error!("upload torrent failed: {}", e);
-            });
+        upload_torrents(&api, &upload).await.unwrap_or_else(|e| {
+            log::error!("upload torrent failed: {}", e);
+        });
 
-        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+        // 只在两次扫描之间响应停止，正在进行的上传会完成
+        tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(60)) => {}
+            _ = shutdown.cancelled() => return Ok(()),
+        }
     }
 }
@@ -1,163 +1,98 @@
+use anyhow::Result;
 use matrix_bot_core::{
-    matrix,
-    matrix_sdk::{
-        self,
-        ruma::events::room::message::{
-            MessageType, OriginalSyncRoomMessageEvent, Relation, TextMessageEventContent,
-        },
-    },
+    command::{self, Access, Command, Context},
+    matrix_sdk::ruma::OwnedRoomId,
+    metrics,
+    template::Vars,
 };
 
 use crate::{
+    api, get_room,
     qbit::ops::{add_torrent, show_status},
-    API, ROOM_MAP,
+    template::TEMPLATES,
 };
 
-pub fn add_listener(room: &matrix::room::Room) {
-    room.0.add_event_handler(
-        |event: OriginalSyncRoomMessageEvent, room: matrix_sdk::room::Room| async move {
-            if let matrix_sdk::room::Room::Joined(room) = room {
-                let msg_body = match event.content.msgtype {
-                    MessageType::Text(TextMessageEventContent { body, .. }) => body,
-                    _ => return,
-                };
-
-                let msg_body = msg_body.trim();
-
-                if msg_body.is_empty() {
-                    return;
-                }
-                if msg_body.starts_with("!download") {
-                    let link = msg_body
-                        .trim_start_matches("!download")
-                        .trim()
-                        .split_ascii_whitespace()
-                        .next()
-                        .unwrap_or_default();
-
-                    let room_id = room.room_id().as_str();
-
-                    let map = ROOM_MAP.get().and_then(|map| map.get(room_id));
-
-                    if let Some((room, setting)) = map {
-                        let result = add_torrent(
-                            API.get().unwrap(),
-                            link,
-                            setting.download_path.as_path(),
-                            room_id,
-                            event.event_id.as_str(),
-                        )
-                        .await;
-
-                        match result {
-                            Ok(_) => {
-                                room.send_relates_msg("添加成功", event.event_id.as_str(), false)
-                                    .await
-                                    .unwrap_or_else(|e| {
-                                        log::error!("send message failed: {}", e);
-                                    });
-                            }
-                            Err(e) => {
-                                room.send_relates_msg(
-                                    &format!("添加失败: {}", e),
-                                    event.event_id.as_str(),
-                                    false,
-                                )
-                                .await
-                                .unwrap_or_else(|e| {
-                                    log::error!("send message failed: {}", e);
-                                });
-                            }
-                        }
-                    }
-                }
-            }
-        },
-    );
-
-    room.0.add_event_handler(
-        |event: OriginalSyncRoomMessageEvent, room: matrix_sdk::room::Room| async move {
-            if let matrix_sdk::room::Room::Joined(room) = room {
-                let msg_body = match event.content.msgtype {
-                    MessageType::Text(TextMessageEventContent { body, .. }) => body,
-                    _ => return,
-                };
-
-                let msg_body = msg_body.trim();
-
-                if msg_body.is_empty() {
-                    return;
-                }
-                if msg_body.starts_with("!qbithelp") {
-                    let room_id = room.room_id().as_str();
-
-                    let map = ROOM_MAP.get().and_then(|map| map.get(room_id));
-
-                    if let Some((room, _setting)) = map {
-                        let msg = "!download <magnet_url> - 添加磁力至下载\n!status - 查看下载状态";
-                        room.send_relates_msg(&msg, event.event_id.as_str(), false)
-                            .await
-                            .unwrap_or_else(|e| {
-                                log::error!("send message failed: {}", e);
-                            });
-                    }
-                }
-            }
-        },
-    );
-
-    room.0.add_event_handler(
-        |event: OriginalSyncRoomMessageEvent, room: matrix_sdk::room::Room| async move {
-            if let matrix_sdk::room::Room::Joined(room) = room {
-                let msg_body = match event.content.msgtype {
-                    MessageType::Text(TextMessageEventContent { body, .. }) => body,
-                    _ => return,
-                };
-
-                let msg_body = msg_body.trim();
-
-                if msg_body.is_empty() {
-                    return;
-                }
-                if msg_body.starts_with("!status") {
-                    let room_id = room.room_id().as_str();
+pub fn register_commands(rooms: Vec<(OwnedRoomId, Access)>) {
+    let mut download_cmd = Command::new("download", download)
+        .usage("<magnet_url>")
+        .help("添加磁力至下载")
+        .rooms(rooms.iter().map(|(room_id, _)| room_id.clone()));
+    let mut status_cmd = Command::new("status", status)
+        .help("查看下载状态，回复添加消息时只显示对应任务")
+        .rooms(rooms.iter().map(|(room_id, _)| room_id.clone()));
+    // 兼容旧版本的帮助命令
+    let help_cmd = Command::new("qbithelp", help)
+        .help(&format!("同 {}help", command::prefix()))
+        .access(Access::everyone())
+        .rooms(rooms.iter().map(|(room_id, _)| room_id.clone()));
+
+    for (room_id, access) in rooms {
+        download_cmd = download_cmd.room_access(room_id.clone(), access.clone());
+        status_cmd = status_cmd.room_access(room_id, access);
+    }
+
+    command::register(download_cmd);
+    command::register(status_cmd);
+    command::register(help_cmd);
+}
 
-                    let map = ROOM_MAP.get().and_then(|map| map.get(room_id));
+async fn help(ctx: Context) -> Result<()> {
+    if let Some(text) = command::help_text(ctx.room.0.room_id(), None) {
+        ctx.reply(&text, false).await?;
+    }
+    Ok(())
+}
 
-                    let reply_event_id = event.content.relates_to.as_ref().and_then(|r| {
-                        if let Relation::Reply { in_reply_to } = r {
-                            let event_id = in_reply_to.event_id.as_str();
-                            Some(event_id.to_string())
-                        } else {
-                            None
-                        }
-                    });
+async fn download(ctx: Context) -> Result<()> {
+    let link = ctx.args.get(0).unwrap_or_default();
+
+    let room_id = ctx.room.0.room_id().as_str();
+    let Some((room, setting)) = get_room(room_id) else {
+        return Ok(());
+    };
+
+    let result = add_torrent(
+        &*api()?,
+        link,
+        setting.download_path.as_path(),
+        room_id,
+        ctx.event.event_id.as_str(),
+    )
+    .await;
+
+    let msg = match result {
+        Ok(_) => {
+            metrics::inc("matrix_bot_torrents_added_total", &[]);
+            TEMPLATES.render("added", &setting.templates, &Vars::new())?
+        }
+        Err(e) => TEMPLATES.render(
+            "add_failed",
+            &setting.templates,
+            &Vars::new().set("error", e.to_string()),
+        )?,
+    };
+    room.send_relates_message(&msg, ctx.event.event_id.as_str())
+        .await?;
+    Ok(())
+}
 
-                    if let Some((room, _setting)) = map {
-                        let msg = show_status(API.get().unwrap(), room_id, reply_event_id).await;
-                        match msg {
-                            Ok((msg, html_msg)) => {
-                                room.send_relates_html(&msg, &html_msg, event.event_id.as_str())
-                                    .await
-                                    .unwrap_or_else(|e| {
-                                        log::error!("send message failed: {}", e);
-                                    });
-                            }
-                            Err(e) => {
-                                room.send_relates_msg(
-                                    &format!("获取失败: {}", e),
-                                    event.event_id.as_str(),
-                                    false,
-                                )
-                                .await
-                                .unwrap_or_else(|e| {
-                                    log::error!("send message failed: {}", e);
-                                });
-                            }
-                        }
-                    }
-                }
-            }
-        },
-    );
+async fn status(ctx: Context) -> Result<()> {
+    let room_id = ctx.room.0.room_id().as_str();
+    let Some((room, setting)) = get_room(room_id) else {
+        return Ok(());
+    };
+
+    let reply_event_id = ctx.in_reply_to().map(|e| e.to_string());
+
+    let msg = match show_status(&*api()?, room_id, reply_event_id, &setting.templates).await {
+        Ok(msg) => msg,
+        Err(e) => TEMPLATES.render(
+            "status_failed",
+            &setting.templates,
+            &Vars::new().set("error", e.to_string()),
+        )?,
+    };
+    room.send_relates_message(&msg, ctx.event.event_id.as_str())
+        .await?;
+    Ok(())
 }
@@ -1,9 +1,11 @@
 use anyhow::Result;
 
-use std::{
-    path::Path,
-    process::{Child, Stdio},
-};
+use std::{path::Path, process::Stdio, time::Duration};
+
+use tokio::process::{Child, Command};
+
+// 等待 qbittorrent-nox 保存状态并退出的时间
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
 
 fn get_download_link() -> Result<String> {
     if cfg!(target_os = "linux") != true {
@@ -38,6 +40,7 @@ fn download_binary(path: impl AsRef<Path>) -> Result<()> {
     Ok(())
 }
 
+/// 启动 qbittorrent-nox，不存在时先下载，会阻塞
 pub fn run(runtime_folder: impl AsRef<Path>, port: u16) -> Result<Child> {
     let binary_path = runtime_folder.as_ref().join("qbittorrent-nox");
     if !binary_path.exists() {
@@ -70,18 +73,56 @@ pub fn run(runtime_folder: impl AsRef<Path>, port: u16) -> Result<Child> {
         )?;
     }
 
-    let mut cmd = std::process::Command::new(binary_path);
+    let mut cmd = Command::new(binary_path);
     cmd.arg(format!("--webui-port={}", port)).arg(format!(
         "--profile={}",
         qbittorrent_folder.to_string_lossy()
     ));
     cmd.stdout(Stdio::null());
+    // 没有调用 `Process::stop` 时（例如 bot 异常退出）直接结束进程，避免留下孤儿进程
+    cmd.kill_on_drop(true);
 
     let child = cmd.spawn()?;
 
     Ok(child)
 }
 
+/// 内置的 qbittorrent-nox 进程，插件停止时调用 [`Process::stop`] 正常关闭
+pub struct Process(Child);
+
+impl Process {
+    pub fn new(child: Child) -> Self {
+        Process(child)
+    }
+
+    /// 发送 SIGTERM 并等待 qbittorrent-nox 保存状态后退出，超时后强制结束
+    pub async fn stop(mut self) {
+        let child = &mut self.0;
+        if let Ok(Some(_)) = child.try_wait() {
+            return;
+        }
+        let Some(pid) = child.id() else {
+            return;
+        };
+
+        log::info!("stop qbittorrent-nox ({})", pid);
+        // SAFETY: pid 来自仍未被回收的子进程
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+
+        match tokio::time::timeout(STOP_TIMEOUT, child.wait()).await {
+            Ok(Ok(status)) => {
+                log::info!("qbittorrent-nox exited: {}", status);
+                return;
+            }
+            Ok(Err(e)) => log::error!("wait qbittorrent-nox failed: {}", e),
+            Err(_) => log::warn!("qbittorrent-nox did not exit in time, kill"),
+        }
+        let _ = child.kill().await;
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -89,11 +130,11 @@ mod test {
 
     use super::*;
 
-    #[test]
-    fn test_running() {
+    #[tokio::test]
+    async fn test_running() {
         let runtime_folder = PathBuf::from("/tmp/qbit");
         let mut child = run(&runtime_folder, 8080).unwrap();
-        std::thread::sleep(std::time::Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_secs(10)).await;
 
         // is still running
         assert!(child.try_wait().unwrap().is_none());
@@ -110,7 +151,8 @@ mod test {
             panic!("no logs");
         }
 
-        child.kill().unwrap();
+        let process = Process::new(child);
+        process.stop().await;
         std::fs::remove_dir_all("/tmp/qbit").unwrap();
     }
 }
@@ -1,6 +1,10 @@
 use std::{collections::HashMap, path::Path};
 
 use anyhow::{Ok, Result};
+use matrix_bot_core::{
+    metrics,
+    template::{Message, Overrides, Vars},
+};
 use once_cell::sync::Lazy;
 use qbit_rs::{
     model::{Credential, GetTorrentListArg, State},
@@ -8,7 +12,7 @@ use qbit_rs::{
 };
 use regex::Regex;
 
-use crate::{upload, ROOM_MAP};
+use crate::{get_room, template::TEMPLATES, upload};
 
 static MAGNET_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"^(magnet:[\?xt\=\w\:\&\;\+\%.]+)").unwrap());
@@ -136,35 +140,24 @@ pub async fn expire_torrents(api: &Qbit, torrents: &ExpireTorrents) -> Result<()
         if room_id.is_some() {
             let room_id = room_id.unwrap();
 
-            let room = ROOM_MAP.get().unwrap().get(room_id);
-
-            if let Some((room, _)) = room {
-                if let Some(name) = torrent.name.as_ref() {
-                    let msg = format!("文件名：{}  \n下载超时，已删除。", name);
-                    match event_id {
-                        Some(event_id) => {
-                            room.send_relates_msg(&msg, event_id, true).await?;
-                            api.delete_tags(vec![event_id.clone()]).await?;
-                        }
-                        None => {
-                            room.send_msg(&msg, true).await?;
-                        }
+            let room = get_room(room_id);
+
+            if let Some((room, setting)) = room {
+                let vars = Vars::new().set("name", torrent.name.clone());
+                let msg = TEMPLATES.render("expired", &setting.templates, &vars)?;
+                match event_id {
+                    Some(event_id) => {
+                        room.send_relates_message(&msg, event_id).await?;
+                        api.delete_tags(vec![event_id.clone()]).await?;
                     }
-                } else {
-                    let msg = "下载超时，已删除。".to_string();
-                    match event_id {
-                        Some(event_id) => {
-                            room.send_relates_msg(&msg, event_id, false).await?;
-                            api.delete_tags(vec![event_id.clone()]).await?;
-                        }
-                        None => {
-                            room.send_msg(&msg, false).await?;
-                        }
+                    None => {
+                        room.send_message(&msg).await?;
                     }
                 }
             }
         }
         log::info!("delete torrent: {}", &hash);
+        metrics::inc("matrix_bot_torrents_expired_total", &[]);
     }
 
     Ok(())
@@ -183,35 +176,23 @@ pub async fn upload_torrents(api: &Qbit, torrents: &UploadTorrents) -> Result<()
         if room_id.is_some() {
             let room_id = room_id.unwrap();
 
-            let room = ROOM_MAP.get().unwrap().get(room_id);
-
-            if let Some((room, _)) = room {
-                if let Some(name) = torrent.name.as_ref() {
-                    let msg = format!(
-                        "文件名：{}  \n下载完成，[点击下载]({})。",
-                        name, download_page
-                    );
-                    match event_id {
-                        Some(event_id) => {
-                            room.send_relates_msg(&msg, event_id, true).await?;
-                            api.delete_tags(vec![event_id.clone()]).await?;
-                        }
-                        None => {
-                            room.send_msg(&msg, true).await?;
-                        }
+            let room = get_room(room_id);
+
+            if let Some((room, setting)) = room {
+                let vars = Vars::new()
+                    .set("name", torrent.name.clone())
+                    .set("url", download_page.as_str());
+                let msg = TEMPLATES.render("finished", &setting.templates, &vars)?;
+                match event_id {
+                    Some(event_id) => {
+                        room.send_relates_message(&msg, event_id).await?;
+                        api.delete_tags(vec![event_id.clone()]).await?;
                     }
-                } else {
-                    let msg = format!("下载完成，[点击下载]({})。", download_page);
-                    match event_id {
-                        Some(event_id) => {
-                            room.send_relates_msg(&msg, event_id, false).await?;
-                            api.delete_tags(vec![event_id.clone()]).await?;
-                        }
-                        None => {
-                            room.send_msg(&msg, false).await?;
-                        }
+                    None => {
+                        room.send_message(&msg).await?;
                     }
                 }
+                metrics::inc("matrix_bot_torrents_uploaded_total", &[]);
             }
             api.delete_torrents(vec![hash.clone()], true).await?;
         }
@@ -224,7 +205,8 @@ pub async fn show_status(
     api: &Qbit,
     room_id: &str,
     event_id: Option<String>,
-) -> Result<(String, String)> {
+    templates: &Overrides,
+) -> Result<Message> {
     let arg = GetTorrentListArg {
         category: Some(room_id.to_string()),
         tag: event_id,
@@ -259,40 +241,15 @@ pub async fn show_status(
         };
 
         let progress = torrent.progress.unwrap_or_default();
-        let progress = format!("{:.2}%", progress * 100.0);
-
-        vec.push((name, state, progress));
-    }
-
-    let mut msg = String::new();
-    for (name, state, progress) in &vec {
-        msg.push_str(&format!("{} {} {}\n", name, state, progress));
-    }
-
-    let mut html_msg = String::from(
-        "<table>
-            <thead>
-            <tr>
-            <th>名称</th>
-            <th>状态</th>
-            <th>进度</th>
-            </tr>
-        </thead>
-        <tbody>",
-    );
-    for (name, state, progress) in vec {
-        html_msg.push_str(&format!(
-            "<tr>
-            <td>{}</td>
-            <td>{}</td>
-            <td>{}</td>
-            </tr>",
-            name, state, progress
-        ));
+        vec.push(
+            Vars::new()
+                .set("name", name)
+                .set("state", state)
+                .set("progress", format!("{:.2}%", progress * 100.0)),
+        );
     }
-    html_msg.push_str("</tbody></table>");
 
-    Ok((msg, html_msg))
+    TEMPLATES.render("status", templates, &Vars::new().set("torrents", vec))
 }
 
 fn extract_non_empty_str(s: &Option<String>) -> Option<&String> {
@@ -3,10 +3,16 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::Result;
-use matrix_bot_core::matrix::{client::Client, room::Room};
+use anyhow::{anyhow, Result};
+use matrix_bot_core::{
+    command::Access,
+    matrix::{client::Client, room::Room},
+    template::Overrides,
+};
 use serde::{Deserialize, Serialize};
 
+use crate::template::TEMPLATES;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Setting {
     pub room: Vec<RoomSetting>,
@@ -21,51 +27,73 @@ pub struct Setting {
 pub struct RoomSetting {
     pub download_path: PathBuf,
     pub room_id: String,
+    /// 不设置时只有权限等级不低于 50 的成员可以使用命令
+    #[serde(default)]
+    pub access: Access,
+    /// 覆盖默认的消息模板
+    #[serde(default, skip_serializing_if = "Overrides::is_empty")]
+    pub templates: Overrides,
 }
 
 impl Setting {
-    pub fn get_or_init(path: impl AsRef<Path>) -> Result<Self> {
-        let setting_path = path.as_ref().join("qbittorrent.toml");
-        // load setting, if not exists, create it and exit
-        let setting = if !setting_path.exists() {
-            log::info!("create setting file: {}", setting_path.to_string_lossy());
-            let setting = Setting {
-                room: vec![RoomSetting {
-                    download_path: path.as_ref().join("qbittorrent").join("download"),
-                    room_id: "".to_string(),
-                }],
-                qbit_user: "admin".to_string(),
-                qbit_pass: "adminadmin".to_string(),
-                qbit_url: "http://127.0.0.1:8080".to_string(),
-                #[cfg(target_os = "linux")]
-                use_internal_qbit: true,
-            };
-            let toml = toml::to_string_pretty(&setting).unwrap();
-            std::fs::write(&setting_path, toml)?;
-            log::error!(
-                "please edit setting file: {}",
-                setting_path.to_string_lossy()
-            );
-            return Err(anyhow::anyhow!(
-                "please edit setting file: {}",
-                setting_path.to_string_lossy()
-            ));
-        } else {
-            log::info!("load setting file: {}", setting_path.to_string_lossy());
-            let toml = std::fs::read_to_string(&setting_path)?;
-            toml::from_str(&toml)?
-        };
-        Ok(setting)
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Setting {
+            room: vec![RoomSetting {
+                download_path: path.as_ref().join("qbittorrent").join("download"),
+                room_id: "".to_string(),
+                access: Access::default(),
+                templates: Overrides::default(),
+            }],
+            qbit_user: "admin".to_string(),
+            qbit_pass: "adminadmin".to_string(),
+            qbit_url: "http://127.0.0.1:8080".to_string(),
+            #[cfg(target_os = "linux")]
+            use_internal_qbit: true,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.room.is_empty() {
+            return Err(anyhow!("`room` is empty"));
+        }
+        for (i, room) in self.room.iter().enumerate() {
+            if room.room_id.trim().is_empty() {
+                return Err(anyhow!("room[{}]: `room_id` is empty", i));
+            }
+            TEMPLATES
+                .validate(&room.templates)
+                .map_err(|e| anyhow!("room[{}]: {}", i, e))?;
+        }
+        url::Url::parse(&self.qbit_url)
+            .map_err(|e| anyhow!("invalid `qbit_url` {}: {}", self.qbit_url, e))?;
+        Ok(())
+    }
+
+    /// qBittorrent 连接相关的配置是否相同
+    pub fn same_connection(&self, other: &Self) -> bool {
+        #[cfg(target_os = "linux")]
+        if self.use_internal_qbit != other.use_internal_qbit {
+            return false;
+        }
+        self.qbit_url == other.qbit_url
+            && self.qbit_user == other.qbit_user
+            && self.qbit_pass == other.qbit_pass
     }
 
     pub async fn to_hashmap(
         &self,
         client: &Client,
     ) -> Result<HashMap<String, (Room, RoomSetting)>> {
+        let mut rooms =
+            Room::resolve_all(client, self.room.iter().map(|s| s.room_id.as_str())).await?;
+
+        // 以真实的房间 ID 为键，命令与种子分类均使用房间 ID
         let mut hashmap = HashMap::new();
         for setting in &self.room {
-            let room = Room::new(client, &setting.room_id).await?;
-            hashmap.insert(setting.room_id.to_string(), (room, setting.clone()));
+            let Some(room) = rooms.remove(&setting.room_id) else {
+                continue;
+            };
+            hashmap.insert(room.0.room_id().to_string(), (room, setting.clone()));
         }
         Ok(hashmap)
     }
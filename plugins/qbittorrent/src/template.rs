@@ -0,0 +1,62 @@
+use matrix_bot_core::template::{Builtin, Templates};
+
+/// 默认模板，可以在房间配置的 `templates` 中按名称覆盖
+pub static TEMPLATES: Templates = Templates::new(&[
+    Builtin {
+        name: "added",
+        plain: "添加成功",
+        html: None,
+    },
+    Builtin {
+        name: "add_failed",
+        plain: "添加失败: {{error}}",
+        html: None,
+    },
+    Builtin {
+        name: "finished",
+        plain: "{{#name}}文件名：{{name}}\n{{/name}}下载完成，下载地址：{{url}}",
+        html: Some(
+            "{{#name}}文件名：{{name}}<br>{{/name}}下载完成，<a href=\"{{url}}\">点击下载</a>。",
+        ),
+    },
+    Builtin {
+        name: "expired",
+        plain: "{{#name}}文件名：{{name}}\n{{/name}}下载超时，已删除。",
+        html: Some("{{#name}}文件名：{{name}}<br>{{/name}}下载超时，已删除。"),
+    },
+    Builtin {
+        name: "status",
+        plain: "{{#torrents}}{{name}} {{state}} {{progress}}\n{{/torrents}}{{^torrents}}没有任务{{/torrents}}",
+        html: Some(
+            "<table><thead><tr><th>名称</th><th>状态</th><th>进度</th></tr></thead><tbody>\
+             {{#torrents}}<tr><td>{{name}}</td><td>{{state}}</td><td>{{progress}}</td></tr>{{/torrents}}\
+             {{^torrents}}<tr><td colspan=\"3\">没有任务</td></tr>{{/torrents}}</tbody></table>",
+        ),
+    },
+    Builtin {
+        name: "status_failed",
+        plain: "获取失败: {{error}}",
+        html: None,
+    },
+]);
+
+#[cfg(test)]
+mod tests {
+    use matrix_bot_core::template::{Overrides, Vars};
+
+    use super::*;
+
+    #[test]
+    fn test_finished() {
+        let vars = Vars::new()
+            .set("name", Some("<b>a</b>"))
+            .set("url", "https://gofile.io/d/a?b=1&c=2");
+        let msg = TEMPLATES
+            .render("finished", &Overrides::default(), &vars)
+            .unwrap();
+        assert_eq!(
+            msg.html.as_deref(),
+            Some("文件名：&lt;b&gt;a&lt;/b&gt;<br>下载完成，<a href=\"https://gofile.io/d/a?b=1&amp;c=2\">点击下载</a>。")
+        );
+    }
+}
@@ -1,4 +1,8 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
 
 use anyhow::Result;
 use axum::{
@@ -7,23 +11,68 @@ use axum::{
     routing::post,
     Json, Router,
 };
-use matrix_bot_core::matrix::{client::Client, room::Room};
+use matrix_bot_core::{
+    async_trait,
+    matrix::{client::Client, room::Room},
+    metrics,
+    plugin::{self, CancellationToken, Plugin, Reload},
+};
 
 use crate::setting::Setting;
 mod setting;
 
-pub async fn run(client: Client, setting_folder: impl AsRef<std::path::Path>) -> Result<()> {
-    log::info!("start webhook");
+type Rooms = Arc<RwLock<HashMap<String, Room>>>;
+
+#[derive(Default)]
+pub struct Webhook {
+    // 与 http 服务共享，修改房间列表时无需重启服务
+    rooms: Rooms,
+}
+
+#[async_trait]
+impl Plugin for Webhook {
+    type Setting = Setting;
+
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn default_setting(&self, _plugin_folder: &std::path::Path) -> Setting {
+        Setting::default()
+    }
+
+    fn validate(&self, setting: &Setting) -> Result<()> {
+        setting.validate()
+    }
 
-    let setting = Setting::get_or_init(setting_folder)?;
+    async fn run(
+        &self,
+        client: Client,
+        setting: Setting,
+        shutdown: CancellationToken,
+    ) -> Result<()> {
+        *self.rooms.write().unwrap() = setting.to_hashmap(&client).await?;
+        run(setting, self.rooms.clone(), shutdown).await
+    }
+
+    async fn reload(&self, client: &Client, old: &Setting, new: &Setting) -> Result<Reload> {
+        // 端口与 token 需要重新创建服务
+        if old.port != new.port || old.token != new.token {
+            return Ok(Reload::Restart);
+        }
+        *self.rooms.write().unwrap() = new.to_hashmap(client).await?;
+        Ok(Reload::Applied)
+    }
+}
+
+async fn run(setting: Setting, rooms: Rooms, shutdown: CancellationToken) -> Result<()> {
     let token = setting.token.clone();
     let port = setting.port;
-    let setting = setting.to_hashmap(&client).await?;
 
     let mut app = Router::new()
         .route("/send/:room_id", post(send))
         .fallback(not_found)
-        .with_state(setting);
+        .with_state(rooms);
 
     if let Some(token) = &token {
         app = app.layer(tower_http::validate_request::ValidateRequestHeaderLayer::bearer(token));
@@ -32,9 +81,12 @@ pub async fn run(client: Client, setting_folder: impl AsRef<std::path::Path>) ->
     log::info!("listen on {}", port);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let server = axum::Server::bind(&addr).serve(app.into_make_service());
+    // 停止时不再接受新请求，等待正在发送的消息完成
+    let server = axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown.cancelled_owned());
 
-    server.await.unwrap();
+    server.await?;
 
     Ok(())
 }
@@ -44,13 +96,15 @@ struct Msg {
     msg: String,
 }
 async fn send(
-    State(room): State<HashMap<String, Room>>,
+    State(rooms): State<Rooms>,
     Path(room_id): Path<String>,
     Json(msg): Json<Msg>,
 ) -> StatusCode {
-    if let Some(room) = room.get(&room_id) {
+    let room = rooms.read().unwrap().get(&room_id).cloned();
+    let code = if let Some(room) = room {
         log::info!("send msg: {}", msg.msg);
-        match room.send_msg(&msg.msg, true).await {
+        // axum 的 handler 不在插件任务内，手动标记归属以便统计
+        match plugin::scope("webhook", room.send_msg(&msg.msg, true)).await {
             Ok(_) => {
                 log::info!("send msg success");
                 StatusCode::OK
@@ -63,7 +117,12 @@ async fn send(
     } else {
         log::error!("room not found: {}", room_id);
         StatusCode::NOT_FOUND
-    }
+    };
+    metrics::inc(
+        "matrix_bot_webhook_requests_total",
+        &[("status", code.as_str())],
+    );
+    code
 }
 
 async fn not_found() -> StatusCode {
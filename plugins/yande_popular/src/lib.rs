@@ -1,34 +1,146 @@
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, path::Path, sync::Mutex};
 
 use anyhow::Result;
 use db::DB;
-use matrix_bot_core::matrix::{client::Client, room::Room};
+use matrix_bot_core::{
+    async_trait,
+    matrix::{client::Client, room::Room},
+    metrics,
+    plugin::{self, CancellationToken, Plugin, Reload},
+    template::Vars,
+};
 use setting::RoomSetting;
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
+};
 
-use crate::setting::Setting;
+use crate::{setting::Setting, template::TEMPLATES};
 
 mod db;
 mod resize;
 mod setting;
+mod template;
 mod yande;
 
-pub async fn run(client: Client, plugin_folder: impl AsRef<Path>) -> Result<()> {
-    log::info!("start yande_popular");
+#[derive(Default)]
+pub struct YandePopular {
+    // 运行中的扫描循环接收新配置
+    update: Mutex<Option<watch::Sender<Setting>>>,
+    // 运行扫描循环的阻塞线程，停止插件时等待它退出，确保数据库已关闭
+    worker: Mutex<Option<Worker>>,
+}
+
+struct Worker {
+    abort: CancellationToken,
+    handle: JoinHandle<()>,
+}
+
+#[async_trait]
+impl Plugin for YandePopular {
+    type Setting = Setting;
+
+    fn name(&self) -> &'static str {
+        "yande_popular"
+    }
+
+    fn default_setting(&self, plugin_folder: &Path) -> Setting {
+        Setting::new(plugin_folder)
+    }
 
-    let setting_hashmap = Setting::get_or_init(plugin_folder)?
-        .to_hashmap(&client)
-        .await?;
+    fn validate(&self, setting: &Setting) -> Result<()> {
+        setting.validate()
+    }
+
+    async fn run(
+        &self,
+        client: Client,
+        setting: Setting,
+        shutdown: CancellationToken,
+    ) -> Result<()> {
+        let (tx, rx) = watch::channel(setting);
+        *self.update.lock().unwrap() = Some(tx);
+
+        // 页面解析使用的 Document 不是 Send，需要在阻塞线程中驱动
+        // 中止插件任务不会结束阻塞线程，由 `abort` 通知它放弃当前操作
+        let abort = CancellationToken::new();
+        let (result_tx, result_rx) = oneshot::channel();
+        let runtime = tokio::runtime::Handle::current();
+        let handle = tokio::task::spawn_blocking({
+            let abort = abort.clone();
+            move || {
+                let result = runtime.block_on(plugin::scope("yande_popular", async {
+                    tokio::select! {
+                        result = run(client, rx, shutdown) => result,
+                        _ = abort.cancelled() => Ok(()),
+                    }
+                }));
+                let _ = result_tx.send(result);
+            }
+        });
+        *self.worker.lock().unwrap() = Some(Worker { abort, handle });
+        result_rx.await?
+    }
+
+    async fn reload(&self, _client: &Client, _old: &Setting, new: &Setting) -> Result<Reload> {
+        // 阻塞线程无法被中止，始终在扫描间隙应用新配置
+        match self.update.lock().unwrap().as_ref() {
+            Some(tx) if tx.send(new.clone()).is_ok() => Ok(Reload::Applied),
+            _ => Ok(Reload::Restart),
+        }
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.update.lock().unwrap().take();
+        // 被强制中止时扫描循环可能仍在运行
+        let worker = self.worker.lock().unwrap().take();
+        if let Some(worker) = worker {
+            worker.abort.cancel();
+            worker.handle.await?;
+        }
+        Ok(())
+    }
+}
+
+async fn run(
+    client: Client,
+    mut update: watch::Receiver<Setting>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let setting = update.borrow_and_update().clone();
+    let mut setting_hashmap = setting.to_hashmap(&client).await?;
 
     loop {
         log::info!("start scan");
-        sync(&setting_hashmap).await.unwrap_or_else(|e| {
+        sync(&setting_hashmap, &shutdown).await.unwrap_or_else(|e| {
             log::error!("scan failed: {}", e);
         });
-        tokio::time::sleep(tokio::time::Duration::from_secs(60 * 60)).await;
+
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(60 * 60)) => {}
+            Ok(_) = update.changed() => {
+                let new = update.borrow_and_update().clone();
+                // 先解析新配置的房间，失败时继续使用旧配置
+                match new.resolve_rooms(&client).await {
+                    Ok(rooms) => {
+                        // 数据库只能被打开一次，先关闭旧的
+                        drop(setting_hashmap);
+                        setting_hashmap = new.open(rooms);
+                        log::info!("apply new setting");
+                    }
+                    Err(e) => log::error!("apply new setting failed, keep old setting: {}", e),
+                }
+            }
+        }
     }
 }
 
-pub async fn sync(setting_hashmap: &HashMap<RoomSetting, (DB, Room)>) -> Result<()> {
+/// 扫描并发送新图片，`shutdown` 取消后发送完当前图片即返回
+pub async fn sync(
+    setting_hashmap: &HashMap<RoomSetting, (DB, Room)>,
+    shutdown: &CancellationToken,
+) -> Result<()> {
     for (setting, (db, room)) in setting_hashmap.iter() {
         log::info!("scan: {}", setting.room_id);
         let mut image_list = Vec::new();
@@ -41,6 +153,10 @@ pub async fn sync(setting_hashmap: &HashMap<RoomSetting, (DB, Room)>) -> Result<
         let download_list = yande::get_download_list(&image_list, db).await?;
 
         for (id, img_data) in download_list {
+            if shutdown.is_cancelled() {
+                log::info!("scan: {} interrupted by shutdown", setting.room_id);
+                return Ok(());
+            }
             for (id, url) in img_data.url.iter() {
                 log::info!("prepare download: {}", id);
                 let path = match yande::download_img(*id, url, &setting.tmp_path).await {
@@ -68,6 +184,7 @@ pub async fn sync(setting_hashmap: &HashMap<RoomSetting, (DB, Room)>) -> Result<
                 match room.send_attachment(&path).await {
                     Ok(_) => {
                         log::info!("upload: {} done", id);
+                        metrics::inc("matrix_bot_yande_images_posted_total", &[]);
                         db.insert(&id.to_string())?;
                     }
                     Err(e) => {
@@ -80,9 +197,11 @@ pub async fn sync(setting_hashmap: &HashMap<RoomSetting, (DB, Room)>) -> Result<
                     log::error!("remove file failed: {}", e);
                 });
             }
-            let msg =
-                format!("来源：[https://yande.re/post/show/{id}](https://yande.re/post/show/{id})");
-            room.send_msg(&msg, true).await?;
+            let vars = Vars::new()
+                .set("id", id.to_string())
+                .set("url", format!("https://yande.re/post/show/{id}"));
+            let msg = TEMPLATES.render("source", &setting.templates, &vars)?;
+            room.send_message(&msg).await?;
         }
         log::info!("scan: {} done", setting.room_id);
         db.auto_remove()?;
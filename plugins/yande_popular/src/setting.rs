@@ -3,11 +3,14 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::Result;
-use matrix_bot_core::matrix::{client::Client, room::Room};
+use anyhow::{anyhow, Result};
+use matrix_bot_core::{
+    matrix::{client::Client, room::Room},
+    template::Overrides,
+};
 use serde::{Deserialize, Serialize};
 
-use crate::db::DB;
+use crate::{db::DB, template::TEMPLATES};
 
 #[derive(Debug, Deserialize, Serialize, Clone, Hash, Eq, PartialEq)]
 pub struct RoomSetting {
@@ -16,55 +19,70 @@ pub struct RoomSetting {
     pub room_id: String,
     pub resize: Option<usize>,
     pub yande_url: Vec<String>,
+    /// 覆盖默认的消息模板
+    #[serde(default, skip_serializing_if = "Overrides::is_empty")]
+    pub templates: Overrides,
 }
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Setting {
     room: Vec<RoomSetting>,
 }
 
 impl Setting {
     pub async fn to_hashmap(&self, client: &Client) -> Result<HashMap<RoomSetting, (DB, Room)>> {
+        let rooms = self.resolve_rooms(client).await?;
+        Ok(self.open(rooms))
+    }
+
+    /// 解析配置中的房间，不打开数据库
+    pub async fn resolve_rooms(&self, client: &Client) -> Result<HashMap<String, Room>> {
+        Room::resolve_all(client, self.room.iter().map(|s| s.room_id.as_str())).await
+    }
+
+    /// 为解析成功的房间打开数据库，同一个数据库只能被打开一次
+    pub fn open(&self, mut rooms: HashMap<String, Room>) -> HashMap<RoomSetting, (DB, Room)> {
         let mut hashmap = HashMap::new();
         for setting in &self.room {
+            let Some(room) = rooms.remove(&setting.room_id) else {
+                continue;
+            };
             let db = DB::open(&setting.db_path);
             std::fs::create_dir_all(Path::new(&setting.tmp_path)).unwrap_or_else(|e| {
                 log::error!("create tmp dir failed: {}", e);
             });
-            let room = Room::new(client, &setting.room_id).await?;
             hashmap.insert(setting.clone(), (db, room));
         }
-        Ok(hashmap)
+        hashmap
     }
 
-    pub fn get_or_init(path: impl AsRef<Path>) -> Result<Self> {
-        let setting_path = path.as_ref().join("yande_popular.toml");
-        // load setting, if not exists, create it and exit
-        let settings: Self = if !setting_path.exists() {
-            log::info!("create setting file: {}", setting_path.to_string_lossy());
-            let settings = Setting {
-                room: vec![RoomSetting {
-                    tmp_path: path.as_ref().join("yande_popular").join("tmp"),
-                    db_path: path.as_ref().join("yande_popular").join("db"),
-                    room_id: "".to_string(),
-                    resize: Some(1920),
-                    yande_url: vec!["https://yande.re/post/popular_recent".to_string()],
-                }],
-            };
-            let toml = toml::to_string_pretty(&settings).unwrap();
-            std::fs::write(&setting_path, toml)?;
-            log::error!(
-                "please edit setting file: {}",
-                setting_path.to_string_lossy()
-            );
-            return Err(anyhow::anyhow!(
-                "please edit setting file: {}",
-                setting_path.to_string_lossy()
-            ));
-        } else {
-            log::info!("load setting file: {}", setting_path.to_string_lossy());
-            let toml = std::fs::read_to_string(&setting_path)?;
-            toml::from_str(&toml)?
-        };
-        Ok(settings)
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Setting {
+            room: vec![RoomSetting {
+                tmp_path: path.as_ref().join("yande_popular").join("tmp"),
+                db_path: path.as_ref().join("yande_popular").join("db"),
+                room_id: "".to_string(),
+                resize: Some(1920),
+                yande_url: vec!["https://yande.re/post/popular_recent".to_string()],
+                templates: Overrides::default(),
+            }],
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.room.is_empty() {
+            return Err(anyhow!("`room` is empty"));
+        }
+        for (i, room) in self.room.iter().enumerate() {
+            if room.room_id.trim().is_empty() {
+                return Err(anyhow!("room[{}]: `room_id` is empty", i));
+            }
+            if room.yande_url.is_empty() {
+                return Err(anyhow!("room[{}]: `yande_url` is empty", i));
+            }
+            TEMPLATES
+                .validate(&room.templates)
+                .map_err(|e| anyhow!("room[{}]: {}", i, e))?;
+        }
+        Ok(())
     }
 }
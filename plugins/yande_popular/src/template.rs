@@ -0,0 +1,8 @@
+use matrix_bot_core::template::{Builtin, Templates};
+
+/// 默认模板，可以在房间配置的 `templates` 中按名称覆盖
+pub static TEMPLATES: Templates = Templates::new(&[Builtin {
+    name: "source",
+    plain: "来源：{{url}}",
+    html: Some("来源：<a href=\"{{url}}\">{{url}}</a>"),
+}]);